@@ -2,19 +2,33 @@ use std;
 use std::io;
 use std::fs::File;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
 
 use libc;
 use copy::copy_file;
 use errors::*;
 use iowrap::Eof;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 
 pub fn align(val: u64) -> u64 {
     (val + 15) / 16 * 16
 }
 
+/// What a pack starts with.
+pub const PACK_HEADER: &[u8; 16] = b"cf2\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+/// Set in a record's extra length if it's been deleted. It still takes up the same space,
+/// so everything after it stays where it was.
+pub const TOMBSTONE: u64 = 1 << 63;
+
+/// Set in a record's extra length until its body has been written and synced. If it's still
+/// set, the writer is still going, or died, and the body can't be trusted.
+pub const UNCOMMITTED: u64 = 1 << 62;
+
 pub struct Record<R>
 where
     R: io::Read,
@@ -22,6 +36,12 @@ where
     pub reader: io::Take<Eof<R>>,
     pub extra: Vec<u8>,
     pub len: u64,
+
+    /// The record has been deleted; its body is whatever was there before, so shouldn't be read.
+    pub tombstone: bool,
+
+    /// The body has been written; if not, it may be anything, including zeros.
+    pub committed: bool,
     realign: u8,
 }
 
@@ -33,6 +53,11 @@ where
         self.extra.len() as u64 + 16 + self.len as u64 + self.realign as u64
     }
 
+    /// Whether the body is a document, not deleted or still being written.
+    pub fn readable(&self) -> bool {
+        self.committed && !self.tombstone
+    }
+
     /// Assuming the reader has been consumed, realign us with where the next record should be.
     pub fn complete(mut self) -> Result<()> {
         let mut buf = vec![0u8; self.realign as usize];
@@ -56,6 +81,9 @@ pub fn read_record<R: io::Read>(fd: R) -> Result<Option<Record<R>>> {
 
     let end = fd.read_u64::<LittleEndian>()?;
     let extra_len = fd.read_u64::<LittleEndian>()?;
+    let tombstone = 0 != extra_len & TOMBSTONE;
+    let committed = 0 == extra_len & UNCOMMITTED;
+    let extra_len = extra_len & !(TOMBSTONE | UNCOMMITTED);
 
     ensure!(end >= 8 + 8, "there isn't even a header, invalid offset?");
     ensure!(
@@ -76,10 +104,36 @@ pub fn read_record<R: io::Read>(fd: R) -> Result<Option<Record<R>>> {
         len,
         reader: fd.take(len),
         extra,
+        tombstone,
+        committed,
         realign: (align(end) - end) as u8,
     }))
 }
 
+/// Delete the record at `pos`, leaving its header saying how long it was, so it can be skipped.
+/// Hold the lock, so a writer can't be committing it at the same time.
+pub fn tombstone(fd: &mut File, pos: u64) -> Result<()> {
+    fd.seek(SeekFrom::Start(pos))?;
+    read_record(&mut *fd)?.ok_or("no record to delete")?;
+
+    // if it's still being written, it still is, and its writer will see it's been deleted
+    let extra_len = extra_len(fd, pos)?;
+    if 0 != extra_len & TOMBSTONE {
+        return Ok(());
+    }
+
+    set_extra_len(fd, pos, extra_len | TOMBSTONE)
+}
+
+/// Change the flags of the record at `pos` under the lock, so it can't undo a `tombstone`.
+fn update_flags<F: FnOnce(u64) -> u64>(fd: &File, pos: u64, update: F) -> Result<()> {
+    flock(fd)?;
+    let updated =
+        extra_len(fd, pos).and_then(|extra_len| set_extra_len(fd, pos, update(extra_len)));
+    unlock_flock(fd)?;
+    updated
+}
+
 pub fn flock(what: &File) -> Result<()> {
     let ret = unsafe { libc::flock(what.as_raw_fd(), libc::LOCK_EX) };
     if 0 != ret {
@@ -98,6 +152,8 @@ pub fn unlock_flock(what: &File) -> Result<()> {
     }
 }
 
+/// Append a record to the pack `fd`, which must be open for reading and writing, and locked,
+/// with `file_end` where it ends. The lock is released while the body is copied.
 pub fn writey_write(
     fd: &mut File,
     file_end: &mut u64,
@@ -112,14 +168,14 @@ pub fn writey_write(
 
     if 0 == *file_end {
         // we locked a new file, write a header
-        fd.write_all(b"cf2\0\0\0\0\0\0\0\0\0\0\0\0\0")?;
+        fd.write_all(PACK_HEADER)?;
         *file_end = 16;
     }
 
     let extra_len: u64 = extra.len() as u64;
     let record_end = 8 + 8 + src_len + extra_len;
     fd.write_u64::<LittleEndian>(record_end)?;
-    fd.write_u64::<LittleEndian>(extra_len)?;
+    fd.write_u64::<LittleEndian>(extra_len | UNCOMMITTED)?;
     fd.write_all(extra)?;
     fd.flush()?;
 
@@ -127,7 +183,40 @@ pub fn writey_write(
 
     unlock_flock(&fd)?;
 
-    copy_file(src, fd, src_len)?;
+    if let Err(e) = copy_file(src, fd, src_len) {
+        let e: Error = e.into();
+
+        // the space is gone either way, but this way nobody has to wonder what's in it
+        return match update_flags(fd, *file_end, |flags| flags | TOMBSTONE) {
+            Ok(()) => Err(e),
+            Err(deleting) => Err(deleting.chain_err(|| {
+                format!(
+                    "deleting the record at {}, after failing to copy its body: {}",
+                    file_end, e
+                )
+            })),
+        };
+    }
+
+    // the body must be on disk before anything can say it is
+    fd.sync_data()?;
+    update_flags(fd, *file_end, |flags| flags & !UNCOMMITTED)?;
+    fd.sync_data()?;
+    Ok(())
+}
+
+/// The extra length, with its flags, of the record at `pos`, without moving `fd`.
+fn extra_len(fd: &File, pos: u64) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    fd.read_exact_at(&mut buf, pos + 8)?;
+    Ok(LittleEndian::read_u64(&buf))
+}
+
+/// Rewrite the extra length, and its flags, of the record at `pos`, without moving `fd`.
+fn set_extra_len(fd: &File, pos: u64, extra_len: u64) -> Result<()> {
+    let mut buf = [0u8; 8];
+    LittleEndian::write_u64(&mut buf, extra_len);
+    fd.write_all_at(&buf, pos + 8)?;
     Ok(())
 }
 
@@ -150,3 +239,85 @@ fn find_by_listing() {
         unlock_flock(file)?
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn flags_survive() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let path = dir.path().join("pack");
+        let src_path = dir.path().join("src");
+        fs::write(&src_path, b"body").unwrap();
+
+        let mut pack = fs::OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut end = 0;
+        flock(&pack).unwrap();
+        writey_write(
+            &mut pack,
+            &mut end,
+            &mut File::open(&src_path).unwrap(),
+            4,
+            b"x",
+        )
+        .unwrap();
+
+        let flags = |pack: &mut File| {
+            pack.seek(SeekFrom::Start(end)).unwrap();
+            let record = read_record(&mut *pack).unwrap().unwrap();
+            (record.tombstone, record.committed)
+        };
+        assert_eq!((false, true), flags(&mut pack));
+
+        // as if it's still being written, then deleted
+        set_extra_len(&pack, end, 1 | UNCOMMITTED).unwrap();
+        tombstone(&mut pack, end).unwrap();
+        assert_eq!((true, false), flags(&mut pack));
+
+        // and its writer finishing doesn't bring it back
+        update_flags(&pack, end, |flags| flags & !UNCOMMITTED).unwrap();
+        assert_eq!((true, true), flags(&mut pack));
+    }
+
+    #[test]
+    fn copy_failure() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let path = dir.path().join("pack");
+        let src_path = dir.path().join("src");
+        fs::write(&src_path, b"body").unwrap();
+
+        let mut pack = fs::OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut end = 0;
+        flock(&pack).unwrap();
+
+        // the source is shorter than it says
+        assert!(writey_write(
+            &mut pack,
+            &mut end,
+            &mut File::open(&src_path).unwrap(),
+            8,
+            b"x",
+        )
+        .is_err());
+
+        pack.seek(SeekFrom::Start(end)).unwrap();
+        let record = read_record(&mut pack).unwrap().unwrap();
+        assert!(record.tombstone);
+        assert!(!record.committed);
+    }
+}
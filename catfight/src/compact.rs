@@ -0,0 +1,162 @@
+//! Deleting records, and rewriting a pack without the deleted ones, to get the space back.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use catfight::align;
+use catfight::flock;
+use catfight::tombstone;
+use catfight::unlock_flock;
+use catfight::PACK_HEADER;
+use errors::*;
+use pack::Pack;
+
+/// Tombstone the records at each of `offsets`, which must be where records start.
+pub fn delete(path: &Path, offsets: &[u64]) -> Result<()> {
+    let starts: HashSet<u64> = {
+        let pack = Pack::open(path)?;
+        let mut starts = HashSet::new();
+        for record in pack.records(PACK_HEADER.len() as u64) {
+            starts.insert(record?.0);
+        }
+        starts
+    };
+
+    for offset in offsets {
+        ensure!(starts.contains(offset), "there's no record at {}", offset);
+    }
+
+    let mut fd = OpenOptions::new().read(true).write(true).open(path)?;
+    flock(&fd)?;
+    for &offset in offsets {
+        tombstone(&mut fd, offset)?;
+    }
+    fd.sync_data()?;
+    unlock_flock(&fd)?;
+
+    Ok(())
+}
+
+/// Copy every record in `pack` which hasn't been deleted into `out`, a new pack, in the same order.
+/// Returns where each record that was kept was, and where it is now. Any uncommitted record
+/// is refused, as it's either still being written, or needs repairing first.
+pub fn compact(pack: &Pack, out: &mut File) -> Result<Vec<(u64, u64)>> {
+    let mut moved = Vec::new();
+
+    {
+        let mut writer = io::BufWriter::new(&mut *out);
+        writer.write_all(PACK_HEADER)?;
+        let mut end = PACK_HEADER.len() as u64;
+
+        for record in pack.records(PACK_HEADER.len() as u64) {
+            let (pos, mut record) = record?;
+            if record.tombstone {
+                continue;
+            }
+
+            ensure!(
+                record.committed,
+                "the record at {} isn't committed; if nothing is writing, repair the pack",
+                pos
+            );
+
+            let extra_len = record.extra.len() as u64;
+            let record_end = 8 + 8 + extra_len + record.len;
+            writer.write_u64::<LittleEndian>(record_end)?;
+            writer.write_u64::<LittleEndian>(extra_len)?;
+            writer.write_all(&record.extra)?;
+
+            let copied = io::copy(&mut record.reader, &mut writer)?;
+            ensure!(copied == record.len, "the record at {} is truncated", pos);
+
+            let padding = align(record_end) - record_end;
+            writer.write_all(&[0u8; 16][..padding as usize])?;
+
+            moved.push((pos, end));
+            end += align(record_end);
+        }
+
+        writer.flush()?;
+    }
+
+    out.sync_all()?;
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+
+    use super::*;
+    use catfight::writey_write;
+
+    #[test]
+    fn delete_and_compact() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let path = dir.path().join("pack");
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        let docs: &[&[u8]] = &[b"one", b"two, which is longer", b"three", b"four"];
+        for body in docs {
+            let src_path = dir.path().join("src");
+            fs::write(&src_path, body).unwrap();
+            let mut end = file.seek(SeekFrom::End(0)).unwrap();
+            flock(&file).unwrap();
+            writey_write(
+                &mut file,
+                &mut end,
+                &mut File::open(&src_path).unwrap(),
+                body.len() as u64,
+                &body[..1],
+            )
+            .unwrap();
+        }
+
+        let starts: Vec<u64> = Pack::open(&path)
+            .unwrap()
+            .records(16)
+            .map(|record| record.unwrap().0)
+            .collect();
+
+        assert!(delete(&path, &[starts[1] + 16]).is_err());
+        delete(&path, &[starts[1], starts[3]]).unwrap();
+
+        let out_path = dir.path().join("compacted");
+        let mut out = File::create(&out_path).unwrap();
+        let moved = compact(&Pack::open(&path).unwrap(), &mut out).unwrap();
+
+        assert_eq!(vec![(starts[0], starts[0]), (starts[2], starts[1])], moved);
+
+        let compacted = Pack::open(&out_path).unwrap();
+        for &(old, new) in &moved {
+            let mut record = compacted.get(new).unwrap();
+            assert!(record.readable());
+
+            let mut body = Vec::new();
+            record.reader.read_to_end(&mut body).unwrap();
+            let doc = docs[starts.iter().position(|&start| start == old).unwrap()];
+            assert_eq!(doc, &body[..]);
+            assert_eq!(&doc[..1], &record.extra[..]);
+        }
+
+        assert_eq!(2, compacted.records(16).count());
+        // "three" is now second, and as long as it was
+        assert_eq!(starts[1] + starts[3] - starts[2], compacted.end().unwrap());
+    }
+}
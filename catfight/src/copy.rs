@@ -3,9 +3,11 @@ use libc;
 use std;
 use std::io;
 use std::fs::File;
+use std::io::Read;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 enum CopyFailure {
+    /// This way of copying doesn't work between these files; nothing has been written.
     Unsupported,
     Errno(io::Error),
 }
@@ -26,62 +28,307 @@ impl MyRawFd for File {
     }
 }
 
-fn try_sendfile(src: &File, dest: &MyRawFd, len: u64) -> Result<(), CopyFailure> {
-    let mut remaining = len;
-    while remaining > 0 {
-        unsafe {
-            let offset: *mut i64 = std::ptr::null_mut();
-            let to_send: usize = std::cmp::min(std::u32::MAX as u64, remaining) as usize;
-            let sent = libc::sendfile(dest.my_raw_fd(), src.as_raw_fd(), offset, to_send as usize);
-
-            if sent == 0 {
-                return Err(CopyFailure::Errno(io::Error::new(
-                    io::ErrorKind::WriteZero,
-                    "sendfile didn't want to send anything",
-                )));
-            }
+/// Most we ask the kernel for at once; it'd stop at about here anyway.
+const MAX_CHUNK: u64 = 0x7fff_f000;
+
+fn short_copy(remaining: u64) -> CopyFailure {
+    CopyFailure::Errno(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("source ended with {} bytes left to copy", remaining),
+    ))
+}
 
-            if sent < 0 {
-                let error = io::Error::last_os_error();
-                if let Some(code) = error.raw_os_error() {
-                    if libc::EAGAIN == code {
-                        continue;
-                    }
+/// Call `copy` until `remaining` is zero, with how much to copy next, which returns how much it did.
+/// The file offsets move along, so if a method turns out not to work, the next can carry on.
+fn syscall_loop<F>(remaining: &mut u64, unsupported: &[i32], copy: F) -> Result<(), CopyFailure>
+where
+    F: Fn(usize) -> isize,
+{
+    let mut tried = false;
+    while *remaining > 0 {
+        let sent = copy(std::cmp::min(MAX_CHUNK, *remaining) as usize);
 
-                    if len == remaining && (libc::EINVAL == code || libc::ENOSYS == code) {
-                        return Err(CopyFailure::Unsupported);
-                    }
+        if sent < 0 {
+            let error = io::Error::last_os_error();
+            if let Some(code) = error.raw_os_error() {
+                if libc::EINTR == code || libc::EAGAIN == code {
+                    continue;
                 }
 
-                return Err(CopyFailure::Errno(error));
+                // only give up on the method if it's never worked; later, it's a real error
+                if !tried && unsupported.contains(&code) {
+                    return Err(CopyFailure::Unsupported);
+                }
             }
-            remaining -= sent as u64;
+
+            return Err(CopyFailure::Errno(error));
+        }
+
+        if 0 == sent {
+            return Err(short_copy(*remaining));
         }
+
+        tried = true;
+        *remaining -= sent as u64;
     }
+
     Ok(())
 }
 
-fn try_streams(src: &mut File, dest: &mut io::Write, len: u64) -> Result<(), ()> {
-    assert_eq!(len, std::io::copy(src, dest).unwrap());
+/// Lets XFS or btrfs share the extents instead of copying, and the kernel copy without us otherwise.
+fn try_copy_file_range(src: &File, dest: &MyRawFd, remaining: &mut u64) -> Result<(), CopyFailure> {
+    // EXDEV: across filesystems, before 5.3 and again between 5.3 and 5.18
+    let unsupported = [
+        libc::ENOSYS,
+        libc::EXDEV,
+        libc::EINVAL,
+        libc::EOPNOTSUPP,
+        libc::EBADF,
+    ];
+
+    syscall_loop(remaining, &unsupported, |len| unsafe {
+        libc::syscall(
+            libc::SYS_copy_file_range,
+            src.as_raw_fd(),
+            std::ptr::null_mut::<libc::loff_t>(),
+            dest.my_raw_fd(),
+            std::ptr::null_mut::<libc::loff_t>(),
+            len,
+            0u32,
+        ) as isize
+    })
+}
+
+fn try_sendfile(src: &File, dest: &MyRawFd, remaining: &mut u64) -> Result<(), CopyFailure> {
+    syscall_loop(remaining, &[libc::EINVAL, libc::ENOSYS], |len| unsafe {
+        libc::sendfile(dest.my_raw_fd(), src.as_raw_fd(), std::ptr::null_mut(), len)
+    })
+}
+
+fn try_streams(
+    src: &mut File,
+    dest: &mut io::Write,
+    remaining: &mut u64,
+) -> Result<(), CopyFailure> {
+    let copied = io::copy(&mut src.take(*remaining), dest).map_err(CopyFailure::Errno)?;
+    *remaining -= copied;
+
+    if 0 != *remaining {
+        return Err(short_copy(*remaining));
+    }
+
     Ok(())
 }
 
+/// Copy `len` bytes from `src`'s position to `dest`'s, moving both along. Tries `copy_file_range`,
+/// then `sendfile`, then reading and writing, each picking up where the last left off.
 pub fn copy_file<T: MyRawFd + io::Write>(
     src: &mut File,
     dest: &mut T,
     len: u64,
 ) -> Result<(), io::Error> {
-    // TODO: copy_file_range
+    let mut remaining = len;
 
-    match try_sendfile(src, dest, len) {
-        Ok(()) => return Ok(()),
-        Err(fail) => match fail {
-            CopyFailure::Errno(x) => return Err(x),
-            CopyFailure::Unsupported => (),
-        },
-    };
+    let result = try_copy_file_range(src, dest, &mut remaining)
+        .or_else(|fail| match fail {
+            CopyFailure::Unsupported => try_sendfile(src, dest, &mut remaining),
+            other => Err(other),
+        })
+        .or_else(|fail| match fail {
+            CopyFailure::Unsupported => try_streams(src, dest, &mut remaining),
+            other => Err(other),
+        });
 
-    try_streams(src, dest, len).unwrap();
+    match result {
+        Ok(()) => Ok(()),
+        Err(CopyFailure::Errno(e)) => Err(e),
+        Err(CopyFailure::Unsupported) => unreachable!("streams are always supported"),
+    }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+    use std::path::Path;
+    use std::process::Command;
+
+    use super::*;
+
+    type Method = fn(&mut File, &mut File, &mut u64) -> Result<(), CopyFailure>;
+
+    fn methods() -> Vec<(&'static str, Method)> {
+        vec![
+            ("copy_file_range", |src, dest, remaining| {
+                try_copy_file_range(src, dest, remaining)
+            }),
+            ("sendfile", |src, dest, remaining| {
+                try_sendfile(src, dest, remaining)
+            }),
+            ("streams", |src, dest, remaining| {
+                try_streams(src, dest, remaining)
+            }),
+        ]
+    }
+
+    /// Not all zeros, and not repeating at any power of two, so a misplaced block shows.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Copy the middle of a file to after a header in another, like `writey_write` does.
+    fn check_copies(src_dir: &Path, dest_dir: &Path) {
+        let data = data(3 * 1024 * 1024 + 7);
+        let src_path = src_dir.join("src");
+        fs::write(&src_path, &data).unwrap();
+
+        for (name, method) in methods().into_iter() {
+            let dest_path = dest_dir.join(format!("dest-{}", name));
+            let mut dest = fs::OpenOptions::new()
+                .create_new(true)
+                .read(true)
+                .write(true)
+                .open(&dest_path)
+                .unwrap();
+            dest.write_all(b"header").unwrap();
+
+            let mut src = File::open(&src_path).unwrap();
+            src.seek(SeekFrom::Start(5)).unwrap();
+            let len = data.len() as u64 - 10;
+            let mut remaining = len;
+
+            match method(&mut src, &mut dest, &mut remaining) {
+                Ok(()) => (),
+                Err(CopyFailure::Unsupported) => {
+                    println!("{} isn't supported here", name);
+                    continue;
+                }
+                Err(CopyFailure::Errno(e)) => panic!("{}: {}", name, e),
+            }
+
+            assert_eq!(0, remaining, "{}", name);
+            assert_eq!(5 + len, src.seek(SeekFrom::Current(0)).unwrap(), "{}", name);
+            assert_eq!(
+                6 + len,
+                dest.seek(SeekFrom::Current(0)).unwrap(),
+                "{}",
+                name
+            );
+
+            let copied = fs::read(&dest_path).unwrap();
+            assert_eq!(b"header", &copied[..6]);
+            assert!(data[5..data.len() - 5] == copied[6..], "{}", name);
+        }
+
+        // and through the chain, in both directions
+        let dest_path = dest_dir.join("dest");
+        let mut dest = File::create(&dest_path).unwrap();
+        copy_file(
+            &mut File::open(&src_path).unwrap(),
+            &mut dest,
+            data.len() as u64,
+        )
+        .unwrap();
+        assert!(data == fs::read(&dest_path).unwrap());
+
+        let back_path = src_dir.join("back");
+        let mut back = File::create(&back_path).unwrap();
+        copy_file(
+            &mut File::open(&dest_path).unwrap(),
+            &mut back,
+            data.len() as u64,
+        )
+        .unwrap();
+        assert!(data == fs::read(&back_path).unwrap());
+    }
+
+    /// Asking for more than there is fails, with everything there was copied, for every method.
+    fn check_short(dir: &Path) {
+        let src_path = dir.join("short");
+        fs::write(&src_path, data(100)).unwrap();
+
+        for (name, method) in methods().into_iter() {
+            let mut dest = File::create(dir.join(format!("short-{}", name))).unwrap();
+            let mut remaining = 150;
+            match method(
+                &mut File::open(&src_path).unwrap(),
+                &mut dest,
+                &mut remaining,
+            ) {
+                Err(CopyFailure::Errno(ref e)) if io::ErrorKind::UnexpectedEof == e.kind() => {}
+                Err(CopyFailure::Unsupported) => continue,
+                _ => panic!("{} didn't notice the source was short", name),
+            }
+            assert_eq!(50, remaining, "{}", name);
+        }
+
+        let mut dest = File::create(dir.join("short-chain")).unwrap();
+        let err = copy_file(&mut File::open(&src_path).unwrap(), &mut dest, 150).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn tmpfs() {
+        let shm = Path::new("/dev/shm");
+        if !shm.is_dir() {
+            println!("no /dev/shm, skipping");
+            return;
+        }
+
+        let dir = tempdir::TempDir::new_in(shm, "catfight-copy").unwrap();
+        check_copies(dir.path(), dir.path());
+        check_short(dir.path());
+    }
+
+    #[test]
+    fn across_filesystems() {
+        let shm = Path::new("/dev/shm");
+        if !shm.is_dir() {
+            println!("no /dev/shm, skipping");
+            return;
+        }
+
+        // how ingest uses it: from a temporary file, into a pack somewhere else
+        let src = tempdir::TempDir::new_in(shm, "catfight-copy").unwrap();
+        let dest = tempdir::TempDir::new("catfight-copy").unwrap();
+        check_copies(src.path(), dest.path());
+    }
+
+    /// Needs root, and xfsprogs, to make and mount a reflink-capable XFS image, like setup.sh's.
+    #[test]
+    #[ignore]
+    fn loopback_xfs() {
+        let dir = tempdir::TempDir::new("catfight-xfs").unwrap();
+        let image = dir.path().join("image");
+        let mount = dir.path().join("mnt");
+        fs::create_dir(&mount).unwrap();
+        File::create(&image)
+            .unwrap()
+            .set_len(512 * 1024 * 1024)
+            .unwrap();
+
+        let run = |command: &mut Command| assert!(command.status().unwrap().success());
+        run(Command::new("mkfs.xfs")
+            .args(&["-q", "-K", "-m", "reflink=1"])
+            .arg(&image));
+        run(Command::new("mount")
+            .args(&["-o", "loop"])
+            .arg(&image)
+            .arg(&mount));
+
+        let result = ::std::panic::catch_unwind(|| {
+            check_copies(&mount, &mount);
+            check_short(&mount);
+
+            let elsewhere = tempdir::TempDir::new("catfight-copy").unwrap();
+            check_copies(elsewhere.path(), &mount);
+        });
+
+        run(Command::new("umount").arg(&mount));
+        result.unwrap();
+    }
 }
@@ -3,14 +3,28 @@ extern crate byteorder;
 extern crate error_chain;
 extern crate iowrap;
 extern crate libc;
+extern crate lz4;
+extern crate sha2;
 
 mod catfight;
+mod compact;
 mod copy;
+mod pack;
+mod verify;
 
+pub use catfight::align;
+pub use catfight::PACK_HEADER;
+pub use catfight::TOMBSTONE;
+pub use catfight::UNCOMMITTED;
 pub use catfight::read_record;
+pub use catfight::Record;
 pub use catfight::flock;
+pub use catfight::tombstone;
 pub use catfight::unlock_flock;
 pub use catfight::writey_write;
+pub use compact::{compact, delete};
+pub use pack::{At, Pack, Records};
+pub use verify::{repair, verify, Damage, Report};
 
 pub use errors::{Error, ErrorKind, Result};
 
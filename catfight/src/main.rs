@@ -0,0 +1,194 @@
+//! Check packs for damage, and make them readable again; delete records, and reclaim their space.
+
+extern crate catfight;
+
+use std::env;
+use std::fs;
+use std::path;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} verify PACK..", program);
+    eprintln!("       {} repair [--truncate] [--force] PACK", program);
+    eprintln!("           (--force: also repair uncommitted records; only if nothing is writing)");
+    eprintln!("       {} delete PACK OFFSET..", program);
+    eprintln!("       {} compact PACK OUT > MAP", program);
+    process::exit(2);
+}
+
+fn print_report(path: &path::Path, report: &catfight::Report) {
+    println!(
+        "{:?}: {} bytes, {} records, {} deleted, {} damaged",
+        path,
+        report.len,
+        report.records,
+        report.tombstones,
+        report.damage.len()
+    );
+
+    for damage in &report.damage {
+        println!(
+            "  {} to {}: {}{}",
+            damage.start,
+            damage.end,
+            damage.reason,
+            if damage.framed {
+                ""
+            } else {
+                " (unreadable from here)"
+            }
+        );
+    }
+}
+
+fn verify(paths: &[&path::Path]) -> bool {
+    let mut healthy = true;
+    for path in paths {
+        match catfight::verify(path) {
+            Ok(report) => {
+                healthy &= report.damage.is_empty();
+                print_report(path, &report);
+            }
+            Err(e) => {
+                healthy = false;
+                println!("{:?}: {}", path, e);
+            }
+        }
+    }
+    healthy
+}
+
+fn repair(path: &path::Path, truncate: bool, force: bool) -> catfight::Result<()> {
+    let (report, len) = catfight::repair(path, truncate, force)?;
+    print_report(path, &report);
+    if report.damage.is_empty() {
+        return Ok(());
+    }
+
+    println!("{:?}: repaired", path);
+    let uncommitted = report
+        .damage
+        .iter()
+        .filter(|damage| damage.uncommitted)
+        .count();
+    if !force && 0 != uncommitted {
+        println!(
+            "{:?}: left {} uncommitted records, which might still be being written; \
+             use --force if nothing is writing",
+            path, uncommitted
+        );
+    }
+    if len < report.len {
+        println!(
+            "{:?}: truncated to {} bytes; rebuild any index of it which goes past that",
+            path, len
+        );
+    }
+
+    Ok(())
+}
+
+/// Write `pack`, without its deleted records, to `out`, printing where each record moved to in
+/// the pack, for `deb2pg-reindex --remap`; not `pos`es.
+fn compact(pack: &path::Path, out: &path::Path) -> catfight::Result<()> {
+    // nothing can be appended while we're copying; anything after would be lost
+    let lock = fs::File::open(pack)?;
+    catfight::flock(&lock)?;
+
+    let source = catfight::Pack::open(pack)?;
+    let mut out_file = fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(out)?;
+
+    let moved = match catfight::compact(&source, &mut out_file) {
+        Ok(moved) => moved,
+        Err(e) => {
+            let _ = fs::remove_file(out);
+            return Err(e);
+        }
+    };
+
+    for (old, new) in &moved {
+        println!("{}\t{}", old, new);
+    }
+
+    let before = fs::metadata(pack)?.len();
+    let after = out_file.metadata()?.len();
+    catfight::unlock_flock(&lock)?;
+
+    eprintln!(
+        "{:?}: kept {} records, {} bytes of {} in {:?}",
+        pack,
+        moved.len(),
+        after,
+        before,
+        out
+    );
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        usage(&args[0]);
+    }
+
+    match args[1].as_str() {
+        "verify" => {
+            let paths: Vec<&path::Path> = args[2..].iter().map(path::Path::new).collect();
+            if !verify(&paths) {
+                process::exit(1);
+            }
+        }
+        "repair" => {
+            let (path, flags) = match args[2..].split_last() {
+                Some(split) => split,
+                None => usage(&args[0]),
+            };
+
+            let (mut truncate, mut force) = (false, false);
+            for flag in flags {
+                match flag.as_str() {
+                    "--truncate" => truncate = true,
+                    "--force" => force = true,
+                    _ => usage(&args[0]),
+                }
+            }
+
+            if let Err(e) = repair(path::Path::new(path), truncate, force) {
+                eprintln!("{:?}: {}", path, e);
+                process::exit(1);
+            }
+        }
+        "delete" => {
+            let path = path::Path::new(&args[2]);
+            let offsets: Vec<u64> = args[3..]
+                .iter()
+                .map(|offset| offset.parse().unwrap_or_else(|_| usage(&args[0])))
+                .collect();
+
+            if offsets.is_empty() {
+                usage(&args[0]);
+            }
+
+            if let Err(e) = catfight::delete(path, &offsets) {
+                eprintln!("{:?}: {}", path, e);
+                process::exit(1);
+            }
+        }
+        "compact" => {
+            if 4 != args.len() {
+                usage(&args[0]);
+            }
+
+            let (pack, out) = (path::Path::new(&args[2]), path::Path::new(&args[3]));
+            if let Err(e) = compact(pack, out) {
+                eprintln!("{:?}: {}", pack, e);
+                process::exit(1);
+            }
+        }
+        _ => usage(&args[0]),
+    }
+}
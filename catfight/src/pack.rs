@@ -0,0 +1,181 @@
+//! Reading records from anywhere in a pack. Reads are `pread`s, so nothing shares a file position,
+//! and one open `Pack` can serve any number of threads.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use catfight::read_record;
+use catfight::Record;
+use catfight::PACK_HEADER;
+use errors::*;
+
+#[derive(Debug)]
+pub struct Pack {
+    file: File,
+}
+
+/// A reader of a pack's file from somewhere in it, on; it doesn't move anything else's position.
+#[derive(Debug)]
+pub struct At<'p> {
+    file: &'p File,
+    pos: u64,
+}
+
+impl<'p> io::Read for At<'p> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Pack {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Pack> {
+        let file = File::open(path)?;
+
+        let mut header = [0u8; 16];
+        file.read_exact_at(&mut header, 0)?;
+        ensure!(header == *PACK_HEADER, "not a pack");
+
+        Ok(Pack { file })
+    }
+
+    /// Where the next record would be written.
+    pub fn end(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    pub fn reader(&self, pos: u64) -> At {
+        At {
+            file: &self.file,
+            pos,
+        }
+    }
+
+    /// The record at `pos`, which has to be the start of one. Nothing past its header is read
+    /// until its `reader` is.
+    pub fn get(&self, pos: u64) -> Result<Record<At>> {
+        match read_record(self.reader(pos))? {
+            Some(record) => Ok(record),
+            None => bail!("no record at {}, past the end of the pack", pos),
+        }
+    }
+
+    /// What was stored alongside the record at `pos`; the body isn't read.
+    pub fn extra(&self, pos: u64) -> Result<Vec<u8>> {
+        Ok(self.get(pos)?.extra)
+    }
+
+    /// Every record from `pos`, which has to be the start of one, with where it starts.
+    /// Deleted and uncommitted records are included; check `readable`.
+    pub fn records(&self, pos: u64) -> Records {
+        Records {
+            pack: self,
+            pos: Some(pos),
+        }
+    }
+}
+
+pub struct Records<'p> {
+    pack: &'p Pack,
+
+    /// Where the next record is, or `None` after the end, or an error.
+    pos: Option<u64>,
+}
+
+impl<'p> Iterator for Records<'p> {
+    type Item = Result<(u64, Record<At<'p>>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.pos?;
+
+        match read_record(self.pack.reader(pos)) {
+            Ok(Some(record)) => {
+                self.pos = Some(pos + record.len());
+                Some(Ok((pos, record)))
+            }
+            Ok(None) => {
+                self.pos = None;
+                None
+            }
+            Err(e) => {
+                self.pos = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+
+    use super::*;
+    use catfight::writey_write;
+
+    #[test]
+    fn random_access() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let path = dir.path().join("pack");
+        let mut file = fs::OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        let docs: &[(&[u8], &[u8])] = &[(b"one", b"x"), (b"", b""), (b"three", b"yyy")];
+        for &(body, extra) in docs {
+            let src_path = dir.path().join("src");
+            fs::write(&src_path, body).unwrap();
+            let mut end = file.seek(SeekFrom::End(0)).unwrap();
+            ::catfight::flock(&file).unwrap();
+            writey_write(
+                &mut file,
+                &mut end,
+                &mut File::open(&src_path).unwrap(),
+                body.len() as u64,
+                extra,
+            )
+            .unwrap();
+        }
+
+        let pack = Pack::open(&path).unwrap();
+        let found: Vec<(u64, Vec<u8>)> = pack
+            .records(16)
+            .map(|record| {
+                let (pos, mut record) = record.unwrap();
+                let mut body = Vec::new();
+                record.reader.read_to_end(&mut body).unwrap();
+                (pos, body)
+            })
+            .collect();
+
+        assert_eq!(3, found.len());
+        for (&(pos, ref body), &(expected, extra)) in found.iter().zip(docs) {
+            assert_eq!(expected, &body[..]);
+            assert_eq!(extra, &pack.extra(pos).unwrap()[..]);
+        }
+
+        // out of order, and interleaved
+        let mut last = pack.get(found[2].0).unwrap();
+        let mut first = pack.get(found[0].0).unwrap();
+        let mut buf = [0u8; 2];
+        last.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(b"th", &buf);
+        first.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(b"on", &buf);
+        last.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(b"re", &buf);
+
+        assert!(pack.get(pack.end().unwrap()).is_err());
+        assert!(Pack::open(dir.path().join("src")).is_err());
+    }
+}
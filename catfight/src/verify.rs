@@ -0,0 +1,460 @@
+//! Checking a pack: its header, the framing of every record, and that each body decompresses
+//! to what the hash in its `extra`, as `ingest` writes it, says it should.
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use lz4;
+use sha2;
+use sha2::Digest;
+
+use catfight::align;
+use catfight::read_record;
+use catfight::PACK_HEADER;
+use errors::*;
+
+/// How long the sha256 `ingest` puts in `extra` is; anything else is only decompressed.
+const HASH_LEN: usize = 256 / 8;
+
+/// A part of the pack which can't be read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Damage {
+    pub start: u64,
+    pub end: u64,
+    pub reason: String,
+
+    /// Whether the record's header is fine, and just its body isn't, so it can be tombstoned.
+    /// If not, nothing from `start` on can be found, and `end` is the end of the pack.
+    pub framed: bool,
+
+    /// Whether the record is only uncommitted, so a writer might still be copying its body.
+    pub uncommitted: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// How long the pack is.
+    pub len: u64,
+
+    /// How many records were found, including damaged and deleted ones.
+    pub records: u64,
+    pub tombstones: u64,
+    pub damage: Vec<Damage>,
+}
+
+impl Report {
+    /// Where the pack stops being readable, if anywhere.
+    pub fn unframed(&self) -> Option<u64> {
+        self.damage
+            .iter()
+            .find(|damage| !damage.framed)
+            .map(|damage| damage.start)
+    }
+}
+
+pub fn verify(path: &Path) -> Result<Report> {
+    let mut fd = File::open(path)?;
+    let mut report = Report {
+        len: fd.metadata()?.len(),
+        ..Report::default()
+    };
+
+    let mut header = [0u8; 16];
+    if report.len < header.len() as u64 || {
+        fd.read_exact(&mut header)?;
+        header != *PACK_HEADER
+    } {
+        report.damage.push(Damage {
+            start: 0,
+            end: report.len,
+            reason: "no pack header".to_string(),
+            framed: false,
+            uncommitted: false,
+        });
+        return Ok(report);
+    }
+
+    let mut pos = header.len() as u64;
+    while pos < report.len {
+        fd.seek(SeekFrom::Start(pos))?;
+        let end = match check_record(&mut fd, pos, report.len) {
+            Ok((end, tombstone)) => {
+                report.records += 1;
+                if tombstone {
+                    report.tombstones += 1;
+                }
+                end
+            }
+            Err(Framing(reason)) => {
+                report.damage.push(Damage {
+                    start: pos,
+                    end: report.len,
+                    reason,
+                    framed: false,
+                    uncommitted: false,
+                });
+                break;
+            }
+            Err(Body(end, reason)) => {
+                report.records += 1;
+                report.damage.push(Damage {
+                    start: pos,
+                    end,
+                    reason,
+                    framed: true,
+                    uncommitted: false,
+                });
+                end
+            }
+            Err(Uncommitted(end)) => {
+                report.records += 1;
+                report.damage.push(Damage {
+                    start: pos,
+                    end,
+                    reason: "uncommitted: still being written, or the writer died".to_string(),
+                    framed: true,
+                    uncommitted: true,
+                });
+                end
+            }
+        };
+
+        pos = end;
+    }
+
+    Ok(report)
+}
+
+/// What's wrong with a record.
+enum Bad {
+    /// The header doesn't make sense, so where the next record is isn't known.
+    Framing(String),
+
+    /// The body doesn't, but the next record is at this offset.
+    Body(u64, String),
+
+    /// The body hasn't been committed, and the next record is at this offset.
+    Uncommitted(u64),
+}
+
+use self::Bad::*;
+
+/// Where the next record starts, and whether this one is a tombstone.
+fn check_record(fd: &mut File, pos: u64, pack_len: u64) -> ::std::result::Result<(u64, bool), Bad> {
+    let mut record = match read_record(&mut *fd) {
+        Ok(Some(record)) => record,
+        Ok(None) => return Err(Framing("truncated record header".to_string())),
+        Err(e) => return Err(Framing(e.to_string())),
+    };
+
+    let end = pos + record.len();
+    if end > pack_len {
+        return Err(Framing(format!(
+            "record runs to {}, past the end of the pack",
+            end
+        )));
+    }
+
+    if 0 != end % 16 {
+        return Err(Framing(format!("record ends unaligned, at {}", end)));
+    }
+
+    if record.tombstone {
+        return Ok((end, true));
+    }
+
+    if !record.committed {
+        return Err(Uncommitted(end));
+    }
+
+    let mut hasher = sha2::Sha256::default();
+    let decoded = lz4::Decoder::new(&mut record.reader).and_then(|mut decoder| {
+        let mut buf = [0u8; 4096 * 16];
+        loop {
+            let read = decoder.read(&mut buf)?;
+            if 0 == read {
+                return Ok(());
+            }
+            hasher.input(&buf[..read]);
+        }
+    });
+
+    if let Err(e) = decoded {
+        return Err(Body(end, format!("can't decompress: {}", e)));
+    }
+
+    if HASH_LEN == record.extra.len() && hasher.result()[..] != record.extra[..] {
+        return Err(Body(end, "hash mismatch".to_string()));
+    }
+
+    Ok((end, false))
+}
+
+/// Verify the pack while it's locked, then tombstone every damaged record which can be, and
+/// truncate at the first which can't, or at the first damage of any kind if `truncate`.
+/// Returns what was found, and the new length of the pack.
+///
+/// Uncommitted records are left alone unless `force`: a writer copies the body after unlocking,
+/// so one might still be being written. Only force it if nothing is writing to the pack.
+pub fn repair(path: &Path, truncate: bool, force: bool) -> Result<(Report, u64)> {
+    let mut fd = ::std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    ::catfight::flock(&fd)?;
+
+    // nothing can be appended or committed while it's locked, so this is still true below
+    let report = verify(path)?;
+
+    let damage: Vec<&Damage> = report
+        .damage
+        .iter()
+        .filter(|damage| force || !damage.uncommitted)
+        .collect();
+
+    let cut = if truncate {
+        damage.first().map(|damage| damage.start)
+    } else {
+        report.unframed()
+    };
+
+    if let Some(cut) = cut {
+        if let Some(skipped) = report
+            .damage
+            .iter()
+            .find(|damage| !force && damage.uncommitted && damage.start >= cut)
+        {
+            bail!(
+                "uncommitted record at {} might still be being written; not truncating before it",
+                skipped.start
+            );
+        }
+    }
+
+    for damage in damage {
+        if cut.map_or(true, |cut| damage.start < cut) {
+            ::catfight::tombstone(&mut fd, damage.start)?;
+        }
+    }
+
+    let len = match cut {
+        Some(cut) => {
+            // if even the header is bad, there's nothing to keep
+            let cut = if cut < PACK_HEADER.len() as u64 {
+                0
+            } else {
+                align(cut)
+            };
+            fd.set_len(cut)?;
+            cut
+        }
+        None => report.len,
+    };
+
+    fd.sync_all()?;
+    ::catfight::unlock_flock(&fd)?;
+    Ok((report, len))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::Write;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::*;
+    use catfight::writey_write;
+
+    /// A pack of the `docs`, with their hashes, as `ingest` writes them.
+    fn pack(dir: &Path, docs: &[&[u8]]) -> (::std::path::PathBuf, Vec<u64>) {
+        let path = dir.join("text-5.0000000000.cfp");
+        let mut pack = fs::OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        let mut poses = Vec::new();
+        for (i, doc) in docs.iter().enumerate() {
+            let src_path = dir.join(format!("src{}", i));
+            {
+                let (_, result) = {
+                    let mut encoder = lz4::EncoderBuilder::new()
+                        .build(File::create(&src_path).unwrap())
+                        .unwrap();
+                    encoder.write_all(doc).unwrap();
+                    encoder.finish()
+                };
+                result.unwrap();
+            }
+
+            let mut src = File::open(&src_path).unwrap();
+            let len = src.metadata().unwrap().len();
+            let mut end = pack.seek(SeekFrom::End(0)).unwrap();
+            ::catfight::flock(&pack).unwrap();
+            writey_write(
+                &mut pack,
+                &mut end,
+                &mut src,
+                len,
+                &sha2::Sha256::digest(doc),
+            )
+            .unwrap();
+            poses.push(if 0 == end { 16 } else { end });
+        }
+
+        (path, poses)
+    }
+
+    fn corrupt(path: &Path, at: u64, bytes: &[u8]) {
+        let mut fd = fs::OpenOptions::new().write(true).open(path).unwrap();
+        fd.seek(SeekFrom::Start(at)).unwrap();
+        fd.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn clean() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let (path, _) = pack(dir.path(), &[b"hello", b"world", b""]);
+
+        let report = verify(&path).unwrap();
+        assert_eq!(3, report.records);
+        assert!(report.damage.is_empty());
+
+        let mut fd = File::open(&path).unwrap();
+        fd.seek(SeekFrom::Start(16)).unwrap();
+        assert!(read_record(&mut fd).unwrap().unwrap().readable());
+    }
+
+    #[test]
+    fn damage() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let (path, poses) = pack(dir.path(), &[b"hello", b"world", b"again"]);
+
+        // a hole of zeros, like a crash during the copy, where the body should be
+        corrupt(&path, poses[1] + 16 + 32, &[0; 8]);
+
+        let report = verify(&path).unwrap();
+        assert_eq!(3, report.records);
+        assert_eq!(1, report.damage.len());
+        assert_eq!(poses[1], report.damage[0].start);
+        assert_eq!(poses[2], report.damage[0].end);
+        assert!(report.damage[0].framed);
+
+        assert_eq!(
+            (report.clone(), report.len),
+            repair(&path, false, false).unwrap()
+        );
+        let report = verify(&path).unwrap();
+        assert_eq!(3, report.records);
+        assert_eq!(1, report.tombstones);
+        assert!(report.damage.is_empty());
+    }
+
+    #[test]
+    fn hash() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let (path, poses) = pack(dir.path(), &[b"hello", b"world"]);
+
+        // still valid lz4, but the wrong document
+        corrupt(&path, poses[0] + 16, &[0; 32]);
+        let report = verify(&path).unwrap();
+        assert_eq!("hash mismatch", report.damage[0].reason);
+    }
+
+    #[test]
+    fn uncommitted() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let (path, poses) = pack(dir.path(), &[b"hello", b"world"]);
+
+        // as if the writer died before it finished the body
+        let mut flagged = [0u8; 8];
+        LittleEndian::write_u64(&mut flagged, 32 | ::catfight::UNCOMMITTED);
+        corrupt(&path, poses[1] + 8, &flagged);
+
+        let mut fd = File::open(&path).unwrap();
+        fd.seek(SeekFrom::Start(poses[1])).unwrap();
+        let record = read_record(&mut fd).unwrap().unwrap();
+        assert_eq!(32, record.extra.len());
+        assert!(!record.readable());
+
+        let report = verify(&path).unwrap();
+        assert_eq!(1, report.damage.len());
+        assert_eq!(poses[1], report.damage[0].start);
+        assert!(report.damage[0].framed);
+        assert!(report.damage[0].uncommitted);
+
+        // a writer might still be copying it
+        assert_eq!(
+            (report.clone(), report.len),
+            repair(&path, false, false).unwrap()
+        );
+        assert_eq!(
+            (report.clone(), report.len),
+            repair(&path, true, false).unwrap()
+        );
+        assert_eq!(report, verify(&path).unwrap());
+
+        // cutting at earlier damage would throw it away
+        corrupt(&path, poses[0] + 16, &[0; 32]);
+        let report = verify(&path).unwrap();
+        assert_eq!(2, report.damage.len());
+        assert!(repair(&path, true, false).is_err());
+        assert_eq!(report, verify(&path).unwrap());
+
+        assert_eq!(
+            (report.clone(), report.len),
+            repair(&path, false, true).unwrap()
+        );
+        let report = verify(&path).unwrap();
+        assert_eq!(2, report.tombstones);
+        assert!(report.damage.is_empty());
+    }
+
+    #[test]
+    fn committed_since() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let (path, poses) = pack(dir.path(), &[b"hello", b"world"]);
+
+        let mut flagged = [0u8; 8];
+        LittleEndian::write_u64(&mut flagged, 32 | ::catfight::UNCOMMITTED);
+        corrupt(&path, poses[1] + 8, &flagged);
+        assert_eq!(1, verify(&path).unwrap().damage.len());
+
+        // the writer finishes before the repair takes the lock
+        LittleEndian::write_u64(&mut flagged, 32);
+        corrupt(&path, poses[1] + 8, &flagged);
+
+        let (report, _) = repair(&path, false, true).unwrap();
+        assert!(report.damage.is_empty());
+        assert_eq!(0, verify(&path).unwrap().tombstones);
+    }
+
+    #[test]
+    fn truncated() {
+        let dir = tempdir::TempDir::new("catfight").unwrap();
+        let (path, poses) = pack(dir.path(), &[b"hello", b"world", b"again"]);
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 8)
+            .unwrap();
+
+        let report = verify(&path).unwrap();
+        assert_eq!(Some(poses[2]), report.unframed());
+
+        assert_eq!((report, poses[2]), repair(&path, false, false).unwrap());
+        let report = verify(&path).unwrap();
+        assert_eq!(2, report.records);
+        assert!(report.damage.is_empty());
+    }
+}
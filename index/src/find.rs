@@ -1,220 +1,1374 @@
 use std;
 
+use std::cmp;
+use std::fmt;
 use std::fs;
-use std::io;
-use std::iter;
+use std::ops;
 use std::path;
 use std::slice;
+use std::str;
+use std::time;
 
-use std::io::Seek;
-use std::io::SeekFrom;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::atomic;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 use lz4;
+use rayon;
+use rayon::prelude::*;
+use regex;
+use regex_syntax;
 
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
 use catfight;
+use errors::*;
 use grep;
+use grep::LineMatch;
+use header;
+use header::Checksum;
+use header::Header;
 use memmap;
 use names;
+use plan;
+use plan::TriQuery;
+use postings::PostingCursor;
+use postings::PostingList;
+use query::Expr;
+use strings;
 use tri;
+use tri::Scheme;
 
-const MAX_TRI: u32 = 64 * 64 * 64;
+/// Compressed postings, as in `postings`, found through the directory. A merged segment's
+/// are wide, with u64 ids; any other's are u32s.
+pub const FORMAT_PACKED: u32 = 2;
 
+/// The length of the directory at the end of a segment: where each trigram's list starts,
+/// then where the last ends.
+fn directory_len(scheme: &Scheme) -> usize {
+    (scheme.max_tri() as usize + 1) * 8
+}
+
+/// Candidates are grepped in chunks of this many, each chunk opening its pack once.
+const CHUNK_LEN: usize = 64;
+
+/// When the index can't help, a search reads at most this many documents before
+/// returning a `next` cursor, like it had hit its `limit`.
+const FULL_SCAN_LEN: u64 = 10_000;
+
+/// One `.idx` file: the trigrams for each of its packs, from the pack's `start` to its `pack_len`.
+/// A pack is indexed by a base segment, from the start, then a delta for each time it grew.
+/// Packs which have stopped growing can be merged into one segment, and searched together.
+#[derive(Debug)]
+pub struct Segment<'f> {
+    pub header: Header,
+
+    /// Where each of the `header.packs` is.
+    pub packs: Vec<path::PathBuf>,
+
+    /// As named by the `header`.
+    pub scheme: &'static Scheme,
+    map: memmap::Mmap,
+
+    /// `directory_len` bytes of offsets into `lists`. A trigram's list runs from its offset
+    /// to the next, and is a u32 count of ids, then the ids, encoded as in `postings`.
+    directory: &'f [u8],
+    lists: &'f [u8],
+
+    /// Whether the ids are u64s, as they are when there's more than one pack.
+    wide: bool,
+}
+
+/// Either all of the segments for a pack, or one merged segment, searched as one.
+///
+/// A document id is the offset of the document in its pack, with the pack's index
+/// in `packs` in the top 32 bits; a single pack's ids are just the offsets.
 #[derive(Debug)]
 struct IndexFile<'f> {
+    packs: Vec<PackFile>,
+
+    /// The packs are of documents which didn't look like text, so are only searched when asked.
+    binary: bool,
+
+    /// If not zero, only runs of this many printable characters were indexed, so that's all we grep.
+    strings: u32,
+
+    segments: Vec<Segment<'f>>,
+}
+
+#[derive(Debug)]
+struct PackFile {
+    path: path::PathBuf,
+
+    /// Kept open, as every search reads from it.
+    pack: catfight::Pack,
     addendum: u64,
-    map: memmap::Mmap,
 
-    /// by_tri.len() === MAX_TRI. The contained slices are zero-or-more local document ids.
-    by_tri: Vec<&'f [u32]>,
-    pack: path::PathBuf,
+    /// How much of the pack was indexed; anything after this was added later.
+    len: u64,
+}
+
+/// The index of the pack in its `IndexFile`, and the offset in that pack, of a document id.
+pub fn split_id(id: u64) -> (usize, u64) {
+    ((id >> 32) as usize, id & 0xffff_ffff)
 }
 
 pub struct Index<'i> {
     files: Vec<IndexFile<'i>>,
+
+    /// Which every file was indexed with, so which queries are planned with.
+    scheme: &'static Scheme,
+
+    /// Candidates are verified on here, which also bounds how much reading we do at once.
+    pool: rayon::ThreadPool,
+}
+
+#[derive(Clone, Default)]
+pub struct SearchOptions {
+    /// How many lines either side of each matching line to return.
+    pub context: usize,
+
+    /// Ignore ASCII case when confirming matches; the index itself is always case insensitive.
+    pub case_insensitive: bool,
+
+    /// Stop once this many documents have matched.
+    pub limit: Option<usize>,
+
+    /// Continue a previous search, from its `SearchResult::next`.
+    pub after: Option<Cursor>,
+
+    /// Give up, returning what we've found so far, at this point.
+    pub deadline: Option<time::Instant>,
+
+    /// Give up, returning what we've found so far, once this has been cancelled.
+    pub cancel: Cancel,
+
+    /// Only grep the candidates this keeps.
+    pub filter: Option<Arc<DocumentFilter>>,
+
+    /// Search the packs of binary documents too, if they've been indexed.
+    pub binary: bool,
+}
+
+/// Narrows down the candidates, by something the index doesn't know about, before they're grepped.
+pub trait DocumentFilter: Send + Sync {
+    /// Which of the `docs` (`pos`es) to keep.
+    fn retain(&self, docs: &[u64]) -> Result<HashSet<u64>>;
+}
+
+impl SearchOptions {
+    fn stop_reason(&self) -> Option<Truncated> {
+        if self.cancel.is_cancelled() {
+            return Some(Truncated::Cancelled);
+        }
+
+        match self.deadline {
+            Some(deadline) if time::Instant::now() >= deadline => Some(Truncated::Deadline),
+            _ => None,
+        }
+    }
+}
+
+/// Stops the searches it's been given to, when `cancel`led, from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct Cancel {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Cancel {
+    pub fn new() -> Cancel {
+        Cancel::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// Why a search gave up before it finished.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Truncated {
+    Cancelled,
+    Deadline,
+}
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Truncated::Cancelled => write!(f, "cancelled"),
+            Truncated::Deadline => write!(f, "out of time"),
+        }
+    }
+}
+
+/// How far through the index a search got: the `local` document id in the `file`th index file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    pub file: usize,
+    pub local: u64,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.file, self.local)
+    }
+}
+
+impl str::FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cursor> {
+        let mut parts = s.splitn(2, '-');
+        match (parts.next().map(str::parse), parts.next().map(str::parse)) {
+            (Some(Ok(file)), Some(Ok(local))) => Ok(Cursor { file, local }),
+            _ => bail!(ErrorKind::InvalidQuery(format!("invalid cursor: '{}'", s))),
+        }
+    }
+}
+
+pub struct DocumentMatch {
+    pub pos: u64,
+    pub lines: Vec<LineMatch>,
 }
 
 pub struct SearchResult {
-    pub docs: Vec<u64>,
+    pub docs: Vec<DocumentMatch>,
     pub grepped: u64,
+
+    /// If the search stopped early, where to continue it from.
+    pub next: Option<Cursor>,
+
+    /// If the search gave up early, why. The `docs` are still valid, but incomplete.
+    pub truncated: Option<Truncated>,
+
+    pub strategy: Strategy,
 }
 
-impl<'i> Index<'i> {
-    pub fn open(mut paths: Vec<path::PathBuf>) -> io::Result<Self> {
-        paths.sort();
-        let mut files = Vec::with_capacity(paths.len());
-        for path in paths {
-            let pack = {
-                let mut tmp = path.clone();
-                tmp.set_file_name(path.file_stem().expect("stem"));
-                tmp
-            };
+/// How the candidates for a search were found.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Every term we needed had trigrams of its own.
+    Trigrams,
 
-            let (size_hint, addendum) =
-                names::addendum_from_path(path.file_name().unwrap().to_str().unwrap());
-            //            if size_hint > 4 {
-            //                println!("limited size index: excluding files in {:?}", path);
-            //                continue;
-            //            }
-            let file = fs::File::open(path)?;
-            let map = unsafe { memmap::MmapOptions::new().map(&file)? };
+    /// A two-character term, looked up as every trigram containing it.
+    Expanded,
 
-            assert_eq!(0, map.len() % std::mem::size_of::<u32>());
-            let nums_len = map.len() / std::mem::size_of::<u32>();
+    /// Nothing narrowed it down, so every document is a candidate, read `FULL_SCAN_LEN` at a time.
+    FullScan,
+}
 
-            let raw = unsafe { slice::from_raw_parts(map.as_ptr() as *const u32, nums_len) };
+impl Strategy {
+    /// The query to find the candidates for `expr` with, using the index as much as we can.
+    fn choose(scheme: &Scheme, expr: &Expr) -> (TriQuery, Strategy) {
+        let query = plan::from_expr(scheme, expr);
+        if TriQuery::All != query {
+            return (query, Strategy::Trigrams);
+        }
 
-            let mut by_tri: Vec<&[u32]> = Vec::new();
-            by_tri.resize(MAX_TRI as usize, &[]);
+        let query = plan::from_expr_expanded(scheme, expr);
+        if TriQuery::All != query {
+            return (query, Strategy::Expanded);
+        }
 
-            let mut cur = 0;
-            loop {
-                // block header / guard
-                let start = raw[cur];
-                cur += 1;
+        (TriQuery::All, Strategy::FullScan)
+    }
+}
 
-                if 0 == start {
-                    assert_eq!(cur, nums_len);
-                    break;
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Strategy::Trigrams => write!(f, "trigrams"),
+            Strategy::Expanded => write!(f, "expanded"),
+            Strategy::FullScan => write!(f, "full scan"),
+        }
+    }
+}
+
+impl<'f> Segment<'f> {
+    /// The document ids in this segment containing the trigram.
+    pub fn postings(&self, tri: u32) -> Result<PostingList<'f>> {
+        ensure!(tri < self.scheme.max_tri(), "invalid trigram {}", tri);
+
+        let at = tri as usize * 8;
+        let start = LittleEndian::read_u64(&self.directory[at..]) as usize;
+        let end = LittleEndian::read_u64(&self.directory[at + 8..]) as usize;
+        if start == end {
+            return Ok(PostingList::empty());
+        }
+
+        let list = match self.lists.get(start..end) {
+            Some(list) if list.len() >= 4 => list,
+            _ => bail!(ErrorKind::BadIndex(format!(
+                "directory entry for trigram {} is invalid",
+                tri
+            ))),
+        };
+
+        PostingList::new(LittleEndian::read_u32(list) as usize, &list[4..], self.wide)
+            .chain_err(|| format!("reading the list for trigram {}", tri))
+    }
+
+    /// Document ids which might satisfy the `query`, or `None` if it could be any of them.
+    fn candidates(&self, query: &TriQuery) -> Result<Option<Vec<u64>>> {
+        Ok(match *query {
+            TriQuery::All => None,
+            TriQuery::Nothing => Some(Vec::new()),
+            TriQuery::Tri(tri) => Some(self.postings(tri)?.to_vec()?),
+            TriQuery::And(ref parts) => {
+                // trigrams are skipped through in place; anything else has to be worked out first
+                let mut lists = Vec::new();
+                let mut worked = Vec::new();
+                for part in parts {
+                    match *part {
+                        TriQuery::Tri(tri) => lists.push(self.postings(tri)?),
+                        _ => worked.extend(self.candidates(part)?),
+                    }
+                }
+
+                if lists.is_empty() && worked.is_empty() {
+                    return Ok(None);
+                }
+
+                Some(find_intersection(
+                    lists
+                        .iter()
+                        .map(|list| list.cursor())
+                        .chain(worked.iter().map(|ids| PostingCursor::ids(ids)))
+                        .collect(),
+                )?)
+            }
+            TriQuery::Or(ref parts) => {
+                let mut union = Vec::new();
+                for part in parts {
+                    match self.candidates(part)? {
+                        Some(ids) => union.extend(ids),
+                        None => return Ok(None),
+                    }
                 }
+                union.sort_unstable();
+                union.dedup();
+                Some(union)
+            }
+        })
+    }
+
+    /// Check that the lists are what was written, which reads the whole file, so `open` doesn't.
+    /// Without this, damage is still found, as an error from `postings`, when a list is read.
+    pub fn verify(&self) -> Result<()> {
+        let mut checksum = Checksum::default();
+        checksum.update(&self.map[self.header.encoded_len()..]);
+        if checksum.value() != self.header.checksum {
+            bail!(ErrorKind::BadIndex("checksum mismatch".to_string()));
+        }
+        Ok(())
+    }
 
-                assert_eq!(0xD81F, start, "{}", cur);
+    /// Check the header against the pack, and find the directory. Nothing else is read until
+    /// it's searched, so this takes the same time however big the segment is.
+    pub fn open(path: &path::Path) -> Result<Segment<'f>> {
+        let file = fs::File::open(path)?;
+        let map = unsafe { memmap::MmapOptions::new().map(&file)? };
 
-                assert_eq!(0, raw[cur]);
-                cur += 1;
-                assert_eq!(0, raw[cur]);
-                cur += 1;
+        let header = Header::from_bytes(&map)?;
 
-                // header length, in records
-                let block_len = raw[cur];
-                cur += 1;
+        let scheme = match tri::scheme(header.scheme) {
+            Some(scheme) => scheme,
+            None => bail!(ErrorKind::BadIndex(format!(
+                "trigram scheme {} isn't supported",
+                header.scheme
+            ))),
+        };
 
-                let mut block_cur = cur;
+        let merged = header.packs.len() > 1;
+        let binary = names::is_binary_pack(&header.packs[0].name);
 
-                cur += 2 * block_len as usize;
+        let mut packs = Vec::with_capacity(header.packs.len());
+        for range in &header.packs {
+            match names::addendum_from_path(&range.name) {
+                Ok((_, addendum)) if addendum != range.addendum => {
+                    bail!(ErrorKind::BadIndex(format!(
+                        "index is for {} at {}, but that pack's documents start at {}",
+                        range.name, range.addendum, addendum
+                    )));
+                }
+                _ => (),
+            }
+
+            if merged && header::PACK_START != range.start {
+                bail!(ErrorKind::BadIndex(format!(
+                    "merged, but only has {} from {}",
+                    range.name, range.start
+                )));
+            }
+
+            if binary != names::is_binary_pack(&range.name) {
+                bail!(ErrorKind::BadIndex(
+                    "merged from both text and binary packs".to_string()
+                ));
+            }
+
+            let pack = path.with_file_name(&range.name);
+            let pack_len = fs::metadata(&pack)
+                .chain_err(|| format!("finding pack {:?}", pack))?
+                .len();
+
+            if pack_len < range.pack_len {
+                bail!(ErrorKind::BadIndex(format!(
+                    "{} bytes of {:?} were indexed, but it's only {} bytes now",
+                    range.pack_len, pack, pack_len
+                )));
+            }
+
+            packs.push(pack);
+        }
 
-                // load all the headers,
-                // cur is updated to skip over all the data
-                for _ in 0..block_len {
-                    let tri = raw[block_cur];
-                    block_cur += 1;
+        let body = &map[header.encoded_len()..];
 
-                    let len = raw[block_cur];
-                    block_cur += 1;
+        // the map lives as long as the segment, and the lists are never used after it's dropped
+        let body: &'f [u8] = unsafe { slice::from_raw_parts(body.as_ptr(), body.len()) };
 
-                    by_tri[tri as usize] = &raw[cur..(len as usize + cur)];
+        if FORMAT_PACKED != header.format {
+            bail!(ErrorKind::BadIndex(format!(
+                "unsupported list format {}",
+                header.format
+            )));
+        }
+
+        let directory_len = directory_len(scheme);
+        if body.len() < directory_len {
+            bail!(ErrorKind::BadIndex("truncated".to_string()));
+        }
+
+        let (lists, directory) = body.split_at(body.len() - directory_len);
+
+        // the rest of the directory is checked as it's used
+        let first = LittleEndian::read_u64(directory);
+        let end = LittleEndian::read_u64(&directory[directory_len - 8..]);
+        if 0 != first || lists.len() as u64 != end {
+            bail!(ErrorKind::BadIndex("truncated".to_string()));
+        }
+
+        Ok(Segment {
+            header,
+            packs,
+            scheme,
+            map,
+            directory,
+            lists,
+            wide: merged,
+        })
+    }
+}
+
+/// Put one pack's segments in order, dropping any which are covered by another,
+/// like the deltas left behind if a compaction was interrupted.
+/// There must be no gaps: each segment carries on exactly where the previous one stopped.
+/// A merged segment has no deltas, so is only ever chained on its own.
+pub fn chain_segments<'f>(mut segments: Vec<Segment<'f>>) -> Result<Vec<Segment<'f>>> {
+    // widest first, so the compacted base wins over the deltas it replaced
+    segments.sort_by_key(|segment| {
+        let range = &segment.header.packs[0];
+        (range.start, cmp::Reverse(range.pack_len))
+    });
+
+    let mut chained: Vec<Segment> = Vec::with_capacity(segments.len());
+    let mut covered = header::PACK_START;
+
+    for segment in segments {
+        {
+            let range = &segment.header.packs[0];
+            let pack = &segment.packs[0];
+
+            if range.pack_len <= covered && !chained.is_empty() {
+                continue;
+            }
+
+            if range.start != covered {
+                bail!(ErrorKind::BadIndex(format!(
+                    "{:?} is indexed up to {}, but the next segment covers {}..{}",
+                    pack, covered, range.start, range.pack_len
+                )));
+            }
+
+            if let Some(first) = chained.first() {
+                if first.header.packs.len() > 1 || segment.header.packs.len() > 1 {
+                    bail!(ErrorKind::BadIndex(format!(
+                        "{:?} is in a merged index, so can't have deltas",
+                        pack
+                    )));
+                }
+
+                if first.header.packs[0].addendum != range.addendum {
+                    bail!(ErrorKind::BadIndex(format!(
+                        "segments of {:?} disagree on where its documents start",
+                        pack
+                    )));
+                }
+
+                if first.header.strings != segment.header.strings {
+                    bail!(ErrorKind::BadIndex(format!(
+                        "segments of {:?} disagree on which text was indexed",
+                        pack
+                    )));
+                }
 
-                    cur += len as usize;
+                if first.header.scheme != segment.header.scheme {
+                    bail!(ErrorKind::BadIndex(format!(
+                        "segments of {:?} disagree on how text was turned into trigrams",
+                        pack
+                    )));
                 }
             }
 
-            files.push(IndexFile {
-                pack,
-                addendum,
-                map,
-                by_tri,
-            });
+            covered = range.pack_len;
         }
-        Ok(Index { files })
+
+        chained.push(segment);
+    }
+
+    Ok(chained)
+}
+
+impl<'f> IndexFile<'f> {
+    fn new(segments: Vec<Segment<'f>>) -> Result<IndexFile<'f>> {
+        let segments = chain_segments(segments)?;
+        let (packs, binary, strings) = {
+            let first = &segments[0];
+            let last = &segments[segments.len() - 1];
+
+            // a merged segment is both first and last; otherwise, there's only one pack
+            let packs = first
+                .header
+                .packs
+                .iter()
+                .zip(&first.packs)
+                .zip(&last.header.packs)
+                .map(|((range, path), last)| {
+                    Ok(PackFile {
+                        path: path.clone(),
+                        pack: catfight::Pack::open(path)
+                            .chain_err(|| format!("opening pack {:?}", path))?,
+                        addendum: range.addendum,
+                        len: last.pack_len,
+                    })
+                })
+                .collect::<Result<Vec<PackFile>>>()?;
+
+            (
+                packs,
+                names::is_binary_pack(&first.header.packs[0].name),
+                first.header.strings,
+            )
+        };
+
+        Ok(IndexFile {
+            packs,
+            binary,
+            strings,
+            segments,
+        })
+    }
+
+    /// The `pos` of a document id.
+    fn pos(&self, id: u64) -> u64 {
+        let (pack, local) = split_id(id);
+        local + self.packs[pack].addendum
     }
 
-    pub fn documents_for_tri(&self, tri: u32) -> Vec<u64> {
+    /// Document ids which might satisfy the `query`, or `None` if it could be any of them.
+    /// Each segment covers a later part of the pack, so their candidates are already in order.
+    fn candidates(&self, query: &TriQuery) -> Result<Option<Vec<u64>>> {
         let mut all = Vec::new();
-        for file in &self.files {
-            all.extend(
-                file.by_tri[tri as usize]
-                    .iter()
-                    .map(|x| *x as u64 + file.addendum),
-            );
+        for segment in &self.segments {
+            match segment.candidates(query)? {
+                Some(ids) => all.extend(ids),
+                None => return Ok(None),
+            }
         }
-        all
+        Ok(Some(all))
     }
 
-    pub fn documents_for_search(&self, search: &str) -> SearchResult {
-        let mut matched = Vec::new();
-        let mut grepped = 0u64;
-        let target = tri::trigrams_full(search);
-        for file in &self.files {
-            let this_file = find_intersection(
-                target
-                    .iter()
-                    .map(|tri| file.by_tri[*tri as usize].iter().peekable())
-                    .collect(),
-            );
-
-            let mut pack = fs::File::open(&file.pack).expect("pack shouldn't be deleted ever");
-            for local in this_file {
-                pack.seek(SeekFrom::Start(local as u64)).expect("seek");
-                let mut entry = catfight::read_record(&mut pack)
-                    .expect("read entry")
-                    .expect("entry present");
-
-                if grep::reader_contains(
-                    search.as_bytes(),
-                    lz4::Decoder::new(&mut entry.reader).unwrap(),
-                ).unwrap()
-                    .is_some()
-                {
-                    matched.push(local as u64 + file.addendum);
+    /// The first `max` document ids in the packs after `after`, by walking the record headers.
+    fn all_documents(&self, after: Option<u64>, max: usize) -> Result<Vec<u64>> {
+        let mut docs = Vec::new();
+
+        for (pack_no, pack_file) in self.packs.iter().enumerate() {
+            if after.map_or(false, |after| pack_no < split_id(after).0) {
+                continue;
+            }
+
+            // skip the pack header
+            for record in pack_file.pack.records(header::PACK_START) {
+                let (local, _) = record?;
+                if local >= pack_file.len || docs.len() == max {
+                    break;
+                }
+
+                let id = (pack_no as u64) << 32 | local;
+                if after.map_or(true, |after| id > after) {
+                    docs.push(id);
                 }
-                grepped += 1;
             }
         }
-        SearchResult {
-            docs: matched,
-            grepped,
+
+        Ok(docs)
+    }
+}
+
+/// The offset of every record in `pack` from `start`, which must be one, to `end`,
+/// by walking the record headers.
+pub fn records(pack: &path::Path, start: u64, end: u64) -> Result<Vec<u64>> {
+    pack_records(&catfight::Pack::open(pack)?, start, end)
+}
+
+fn pack_records(pack: &catfight::Pack, start: u64, end: u64) -> Result<Vec<u64>> {
+    let mut offsets = Vec::new();
+    for record in pack.records(start) {
+        let (local, _) = record?;
+        if local >= end {
+            break;
         }
+        offsets.push(local);
     }
+
+    Ok(offsets)
 }
 
-fn find_intersection(mut slices: Vec<iter::Peekable<slice::Iter<u32>>>) -> Vec<u32> {
-    slices.sort_unstable_by_key(|iter| iter.size_hint().0);
+impl<'i> Index<'i> {
+    /// Open every index file, failing if any of them are unusable.
+    /// The base and delta segments for each pack are merged, so it's searched as one.
+    pub fn open(paths: Vec<path::PathBuf>) -> Result<Self> {
+        let mut by_pack: BTreeMap<path::PathBuf, Vec<Segment>> = BTreeMap::new();
+        let mut merged = Vec::new();
+        for path in paths {
+            let segment = Segment::open(&path).chain_err(|| format!("opening {:?}", path))?;
+            if segment.packs.len() > 1 {
+                merged.push(segment);
+                continue;
+            }
+
+            by_pack
+                .entry(segment.packs[0].clone())
+                .or_insert_with(Vec::new)
+                .push(segment);
+        }
 
-    let mut intersection: Vec<u32> = Vec::new();
+        let mut files = Vec::with_capacity(by_pack.len() + merged.len());
+        for (pack, segments) in by_pack {
+            files.push(IndexFile::new(segments).chain_err(|| format!("merging {:?}", pack))?);
+        }
+        for segment in merged {
+            files.push(IndexFile::new(vec![segment])?);
+        }
 
-    loop {
-        // find the largest value at the head of a list; the next possible viable document
-        let mut max = std::u32::MIN;
-        for slice in &mut slices {
-            match slice.peek() {
-                Some(val) => if **val > max {
-                    max = **val;
-                },
-                None => return intersection,
+        files.sort_by(|left, right| left.packs[0].path.cmp(&right.packs[0].path));
+
+        // otherwise, its documents would be found twice
+        let mut seen = HashSet::new();
+        for file in &files {
+            for pack in &file.packs {
+                if !seen.insert(&pack.path) {
+                    bail!(ErrorKind::BadIndex(format!(
+                        "{:?} is in more than one index; remove all but one",
+                        pack.path
+                    )));
+                }
             }
         }
 
-        // move everything up to this value, remembering whether a move was necessary
-        let mut advanced = false;
-        for slice in &mut slices {
+        // a query is planned once, so it has to mean the same thing in every file
+        let scheme = files
+            .first()
+            .map_or(&tri::Simplified as &Scheme, |file| file.segments[0].scheme);
+        for file in &files {
+            if file.segments[0].scheme.id() != scheme.id() {
+                bail!(ErrorKind::BadIndex(format!(
+                    "{:?} uses trigram scheme {}, but {:?} uses {}; reindex them the same way",
+                    file.packs[0].path,
+                    file.segments[0].scheme.id(),
+                    files[0].packs[0].path,
+                    scheme.id()
+                )));
+            }
+        }
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .thread_name(|i| format!("index-grep-{}", i))
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        Ok(Index {
+            files,
+            scheme,
+            pool,
+        })
+    }
+
+    pub fn documents_for_tri(&self, tri: u32) -> Result<Vec<u64>> {
+        let mut all = Vec::new();
+        for file in &self.files {
+            for segment in &file.segments {
+                all.extend(
+                    segment
+                        .postings(tri)?
+                        .to_vec()?
+                        .into_iter()
+                        .map(|id| file.pos(id)),
+                );
+            }
+        }
+        Ok(all)
+    }
+
+    pub fn documents_for_search(
+        &self,
+        search: &str,
+        options: &SearchOptions,
+    ) -> Result<SearchResult> {
+        if search.is_empty() {
+            return Ok(SearchResult {
+                docs: Vec::new(),
+                grepped: 0,
+                next: None,
+                truncated: None,
+                strategy: Strategy::Trigrams,
+            });
+        }
+
+        let (query, strategy) = Strategy::choose(self.scheme, &Expr::Term(search.to_string()));
+
+        if options.case_insensitive {
+            let matcher = grep::Folded::new(search.as_bytes());
+            self.grep_candidates(&query, strategy, &matcher, options)
+        } else {
+            self.grep_candidates(&query, strategy, search.as_bytes(), options)
+        }
+    }
+
+    /// Plan a trigram query from the boolean `expr`, then confirm each candidate by finding
+    /// all of the terms in it at once.
+    pub fn documents_for_query(
+        &self,
+        expr: &Expr,
+        options: &SearchOptions,
+    ) -> Result<SearchResult> {
+        let matcher = grep::Terms::new(expr, options.case_insensitive);
+        if matcher.wants_nothing() {
+            bail!(ErrorKind::InvalidQuery(
+                "there's nothing to look for, only things to avoid".to_string()
+            ));
+        }
+
+        let (query, strategy) = Strategy::choose(self.scheme, expr);
+        self.grep_candidates(&query, strategy, &matcher, options)
+    }
+
+    /// Plan a trigram query from the regex, then confirm each candidate by running the regex over it.
+    pub fn documents_for_regex(
+        &self,
+        pattern: &str,
+        options: &SearchOptions,
+    ) -> Result<SearchResult> {
+        let hir = regex_syntax::ParserBuilder::new()
+            .allow_invalid_utf8(true)
+            .case_insensitive(options.case_insensitive)
+            .build()
+            .parse(pattern)?;
+
+        if plan::spans_lines(&hir) {
+            bail!(ErrorKind::InvalidQuery(format!(
+                "'{}' only matches across lines, but each line is searched on its own",
+                pattern
+            )));
+        }
+
+        let query = plan::from_regex(self.scheme, &hir);
+        if TriQuery::All == query {
+            bail!(ErrorKind::InvalidQuery(format!(
+                "'{}' doesn't require any trigrams, so would have to search every document",
+                pattern
+            )));
+        }
+
+        let regex = regex::bytes::RegexBuilder::new(pattern)
+            .case_insensitive(options.case_insensitive)
+            .build()?;
+
+        self.grep_candidates(&query, Strategy::Trigrams, &regex, options)
+    }
+
+    /// Grep the documents which satisfy the `query`, in index order, starting after
+    /// `options.after`, and stopping once `options.limit` of them have matched,
+    /// or we run out of time.
+    fn grep_candidates<M: grep::Matcher + Sync + ?Sized>(
+        &self,
+        query: &TriQuery,
+        strategy: Strategy,
+        matcher: &M,
+        options: &SearchOptions,
+    ) -> Result<SearchResult> {
+        let mut progress = Progress {
+            matched: Vec::new(),
+            grepped: 0,
+            strategy,
+            // local id zero is the pack header, so this is before every document
+            last: options.after.unwrap_or(Cursor { file: 0, local: 0 }),
+        };
+
+        // The candidate lists for the files in the current round, and the chunks of them to grep.
+        let mut lists: Vec<(usize, Vec<u64>)> = Vec::new();
+        let mut chunks: Vec<(usize, ops::Range<usize>)> = Vec::new();
+
+        let round_len = self.pool.current_num_threads() * 4;
+
+        // How many documents a full scan has taken, grepped or filtered out.
+        let mut scanned = 0;
+
+        for (file_no, file) in self.files.iter().enumerate() {
+            let skip = match options.after {
+                Some(ref after) if file_no < after.file => continue,
+                Some(ref after) if file_no == after.file => Some(after.local),
+                _ => None,
+            };
+
+            if file.binary && !options.binary {
+                continue;
+            }
+
+            if let Some(reason) = options.stop_reason() {
+                return Ok(progress.finish(Some(Stop::Truncated(reason))));
+            }
+
+            let candidates = match file.candidates(query)? {
+                Some(candidates) => candidates,
+                None => {
+                    let all = file.all_documents(skip, FULL_SCAN_LEN as usize - scanned)?;
+                    scanned += all.len();
+                    all
+                }
+            };
+
+            let first = match skip {
+                Some(local) => match candidates.binary_search(&local) {
+                    Ok(idx) => idx + 1,
+                    Err(idx) => idx,
+                },
+                None => 0,
+            };
+
+            lists.push((file_no, candidates));
+
+            // Where the next chunk of this file starts; a round can end part way through it.
+            let mut start = first;
             loop {
-                let next = **match slice.peek() {
-                    Some(new) => new,
-                    None => return intersection,
-                };
-                if next >= max {
+                let list = lists.len() - 1;
+                let len = lists[list].1.len();
+                while start < len && chunks.len() < round_len {
+                    let end = std::cmp::min(start + CHUNK_LEN, len);
+                    chunks.push((list, start..end));
+                    start = end;
+                }
+
+                if chunks.len() < round_len {
                     break;
                 }
 
-                advanced = true;
-                slice.next();
+                let stop = self.grep_round(&lists, &chunks, matcher, options, &mut progress)?;
+                if stop.is_some() {
+                    return Ok(progress.finish(stop));
+                }
+
+                chunks.clear();
+                let current = lists.pop().expect("pushed above");
+                lists.clear();
+                if start == len {
+                    break;
+                }
+                lists.push(current);
+            }
+
+            if scanned == FULL_SCAN_LEN as usize {
+                let stop = self.grep_round(&lists, &chunks, matcher, options, &mut progress)?;
+                return Ok(progress.finish(stop.or(Some(Stop::Limit))));
             }
         }
 
-        // if we didn't move anything up to the max, then we found a value
-        if !advanced {
-            intersection.push(max);
-            for slice in &mut slices {
-                if slice.next().is_none() {
-                    return intersection;
+        let stop = self.grep_round(&lists, &chunks, matcher, options, &mut progress)?;
+        Ok(progress.finish(stop))
+    }
+
+    /// Grep all the `chunks` on the pool, then record the results in order,
+    /// returning why we stopped, if we need to stop.
+    fn grep_round<M: grep::Matcher + Sync + ?Sized>(
+        &self,
+        lists: &[(usize, Vec<u64>)],
+        chunks: &[(usize, ops::Range<usize>)],
+        matcher: &M,
+        options: &SearchOptions,
+        progress: &mut Progress,
+    ) -> Result<Option<Stop>> {
+        let keep = match options.filter {
+            Some(ref filter) => {
+                let docs: Vec<u64> = chunks
+                    .iter()
+                    .flat_map(|&(list, ref range)| {
+                        let (file_no, ref candidates) = lists[list];
+                        let file = &self.files[file_no];
+                        candidates[range.clone()]
+                            .iter()
+                            .map(move |&id| file.pos(id))
+                    })
+                    .collect();
+                Some(filter.retain(&docs)?)
+            }
+            None => None,
+        };
+
+        // `None` for documents which were filtered out, rather than grepped.
+        let results: Vec<Result<(Vec<(u64, Option<Vec<LineMatch>>)>, Option<Truncated>)>> =
+            self.pool.install(|| {
+                chunks
+                    .par_iter()
+                    .map(|&(list, ref range)| {
+                        let (file_no, ref candidates) = lists[list];
+                        let file = &self.files[file_no];
+
+                        let mut found = Vec::with_capacity(range.len());
+                        for &id in &candidates[range.clone()] {
+                            if let Some(reason) = options.stop_reason() {
+                                return Ok((found, Some(reason)));
+                            }
+                            if let Some(ref keep) = keep {
+                                if !keep.contains(&file.pos(id)) {
+                                    found.push((id, None));
+                                    continue;
+                                }
+                            }
+
+                            let (pack_no, local) = split_id(id);
+                            let pack = &file.packs[pack_no].pack;
+                            let lines = grep_document(pack, local, file.strings, matcher, options)?;
+                            found.push((id, Some(lines)));
+                        }
+                        Ok((found, None))
+                    })
+                    .collect()
+            });
+
+        for (&(list, _), result) in chunks.iter().zip(results) {
+            let file_no = lists[list].0;
+            let (found, truncated) = result?;
+            for (id, lines) in found {
+                progress.last = Cursor {
+                    file: file_no,
+                    local: id,
+                };
+
+                let lines = match lines {
+                    Some(lines) => lines,
+                    None => continue,
+                };
+
+                progress.grepped += 1;
+
+                if lines.is_empty() {
+                    continue;
+                }
+
+                progress.matched.push(DocumentMatch {
+                    pos: self.files[file_no].pos(id),
+                    lines,
+                });
+
+                if options
+                    .limit
+                    .map_or(false, |limit| progress.matched.len() >= limit)
+                {
+                    return Ok(Some(Stop::Limit));
                 }
             }
+
+            // Later chunks may have got further, but we can only continue from the first gap.
+            if let Some(reason) = truncated {
+                return Ok(Some(Stop::Truncated(reason)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// What a search has found so far.
+struct Progress {
+    matched: Vec<DocumentMatch>,
+    grepped: u64,
+    strategy: Strategy,
+
+    /// The last candidate we grepped, in index order.
+    last: Cursor,
+}
+
+enum Stop {
+    Limit,
+    Truncated(Truncated),
+}
+
+impl Progress {
+    fn finish(self, stop: Option<Stop>) -> SearchResult {
+        let (next, truncated) = match stop {
+            None => (None, None),
+            Some(Stop::Limit) => (Some(self.last), None),
+            Some(Stop::Truncated(reason)) => (Some(self.last), Some(reason)),
+        };
+
+        SearchResult {
+            docs: self.matched,
+            grepped: self.grepped,
+            next,
+            truncated,
+            strategy: self.strategy,
         }
     }
 }
 
+/// Find the lines in the document at `local` in the `pack` which the `matcher` matches.
+/// If `strings` isn't zero, only look at the runs of that many printable characters, as they were indexed.
+fn grep_document<M: grep::Matcher + ?Sized>(
+    pack: &catfight::Pack,
+    local: u64,
+    strings: u32,
+    matcher: &M,
+    options: &SearchOptions,
+) -> Result<Vec<LineMatch>> {
+    let mut entry = pack.get(local)?;
+    if !entry.readable() {
+        return Ok(Vec::new());
+    }
+
+    let mut decoder = lz4::Decoder::new(&mut entry.reader)?;
+
+    if 0 == strings && !matcher.wants_document() {
+        return Ok(grep::reader_lines(matcher, decoder, options.context)?);
+    }
+
+    // decompress it once, for both the matcher and the lines
+    let mut document = Vec::new();
+    decoder.read_to_end(&mut document)?;
+
+    if 0 == strings {
+        if !matcher.accepts_document(&document) {
+            return Ok(Vec::new());
+        }
+
+        return Ok(grep::reader_lines(matcher, &document[..], options.context)?);
+    }
+
+    // Each run of text is grepped as a line, but reported where it is in the document.
+    let runs = strings::runs(&document, strings as usize);
+    let text = strings::lines(&runs);
+    if !matcher.accepts_document(&text) {
+        return Ok(Vec::new());
+    }
+
+    let mut lines = grep::reader_lines(matcher, &text[..], options.context)?;
+
+    let mut counted = 0;
+    let mut newlines = 0;
+    for line in &mut lines {
+        let start = runs[line.line as usize - 1].0;
+        newlines += document[counted..start]
+            .iter()
+            .filter(|&&byte| b'\n' == byte)
+            .count();
+        counted = start;
+
+        line.line = 1 + newlines as u64;
+        line.offset = start as u64;
+    }
+
+    Ok(lines)
+}
+
+/// The ids in every list, leapfrogging: each cursor seeks to the largest id seen so far,
+/// so long runs which can't match are skipped rather than read.
+fn find_intersection(mut cursors: Vec<PostingCursor>) -> Result<Vec<u64>> {
+    cursors.sort_unstable_by_key(|cursor| cursor.len());
+
+    let mut intersection: Vec<u64> = Vec::new();
+    if cursors.is_empty() {
+        return Ok(intersection);
+    }
+
+    let mut target = 0;
+
+    'candidate: loop {
+        for cursor in &mut cursors {
+            match cursor.seek(target)? {
+                Some(found) if found > target => {
+                    // the next possible viable document; start again with the shortest list
+                    target = found;
+                    continue 'candidate;
+                }
+                Some(_) => (),
+                None => return Ok(intersection),
+            }
+        }
+
+        // everything was already there
+        intersection.push(target);
+        target = match target.checked_add(1) {
+            Some(next) => next,
+            None => return Ok(intersection),
+        };
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::find_intersection;
+    extern crate tempdir;
+
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::Write;
+    use std::path;
+    use std::sync::Mutex;
+
+    use byteorder::ByteOrder;
+    use byteorder::LittleEndian;
+    use lz4;
+
+    use super::*;
+    use catfight;
+    use header;
+    use names;
+    use postings;
+    use postings::PostingCursor;
+    use tri;
+
+    const PACK: &str = "text-5.0000000000000000000000";
+
+    /// A pack of the `docs`, and an index of all of it, in `dir`, as `ingest` and `reindex`
+    /// write them. Returns the index's path, and each document's `pos`, as searches report it.
+    fn write_index(dir: &path::Path, docs: &[&[u8]]) -> (path::PathBuf, Vec<u64>) {
+        let scheme = &tri::Simplified;
+        let addendum = names::addendum_from_path(PACK).unwrap().1;
+        let mut pack = catfight::PACK_HEADER.to_vec();
+        let mut poses = Vec::with_capacity(docs.len());
+        let mut by_tri: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+
+        for doc in docs {
+            let mut encoder = lz4::EncoderBuilder::new().build(Vec::new()).unwrap();
+            encoder.write_all(doc).unwrap();
+            let (body, result) = encoder.finish();
+            result.unwrap();
+
+            let pos = pack.len() as u64;
+            let end = 16 + body.len() as u64;
+            let mut record = vec![0u8; catfight::align(end) as usize];
+            LittleEndian::write_u64(&mut record, end);
+            record[16..end as usize].copy_from_slice(&body);
+            pack.extend(record);
+
+            for tri in scheme.trigrams_bytes(doc) {
+                by_tri.entry(tri).or_insert_with(Vec::new).push(pos as u32);
+            }
+            poses.push(addendum + pos);
+        }
+
+        fs::write(dir.join(PACK), &pack).unwrap();
+
+        let mut body = Vec::new();
+        let mut directory = Vec::new();
+        for tri in 0..scheme.max_tri() + 1 {
+            directory.push(body.len() as u64);
+            if let Some(ids) = by_tri.get_mut(&tri) {
+                ids.sort();
+                let mut count = [0u8; 4];
+                LittleEndian::write_u32(&mut count, ids.len() as u32);
+                body.extend(&count);
+                body.extend(postings::encode(ids));
+            }
+        }
+        for offset in directory {
+            let mut entry = [0u8; 8];
+            LittleEndian::write_u64(&mut entry, offset);
+            body.extend(&entry);
+        }
+
+        let mut checksum = Checksum::default();
+        checksum.update(&body);
+        let header = Header {
+            scheme: scheme.id(),
+            checksum: checksum.value(),
+            strings: 0,
+            format: FORMAT_PACKED,
+            packs: vec![header::PackRange {
+                name: PACK.to_string(),
+                addendum,
+                start: header::PACK_START,
+                pack_len: pack.len() as u64,
+            }],
+        };
+
+        let idx = dir.join(format!("{}.idx", PACK));
+        let mut file = header.to_bytes().unwrap();
+        file.extend(body);
+        fs::write(&idx, file).unwrap();
+
+        (idx, poses)
+    }
+
+    /// Keeps everything, remembering how many documents it was asked about.
+    #[derive(Default)]
+    struct Counting {
+        asked: Mutex<usize>,
+    }
+
+    impl DocumentFilter for Counting {
+        fn retain(&self, docs: &[u64]) -> Result<HashSet<u64>> {
+            *self.asked.lock().unwrap() += docs.len();
+            Ok(docs.iter().cloned().collect())
+        }
+    }
+
+    #[test]
+    fn limited_rounds() {
+        let dir = tempdir::TempDir::new("index").unwrap();
+        let index = Index::open(Vec::new()).unwrap();
+        let round_len = index.pool.current_num_threads() * 4 * CHUNK_LEN;
+
+        // every document matches, and they're all in one file
+        let docs: Vec<Vec<u8>> = (0..round_len * 2 + 1)
+            .map(|no| format!("hello {}", no).into_bytes())
+            .collect();
+        let docs: Vec<&[u8]> = docs.iter().map(|doc| &doc[..]).collect();
+        let (idx, poses) = write_index(dir.path(), &docs);
+        let index = Index::open(vec![idx]).unwrap();
+
+        let counting = Arc::new(Counting::default());
+        let mut options = SearchOptions {
+            limit: Some(1),
+            filter: Some(counting.clone()),
+            ..SearchOptions::default()
+        };
+
+        let found = index.documents_for_search("hello", &options).unwrap();
+        assert_eq!(
+            vec![poses[0]],
+            found.docs.iter().map(|doc| doc.pos).collect::<Vec<_>>()
+        );
+        assert!(*counting.asked.lock().unwrap() <= round_len);
+
+        // and it carries on from there
+        options.after = found.next;
+        let found = index.documents_for_search("hello", &options).unwrap();
+        assert_eq!(
+            vec![poses[1]],
+            found.docs.iter().map(|doc| doc.pos).collect::<Vec<_>>()
+        );
+        assert!(*counting.asked.lock().unwrap() <= 2 * round_len);
+    }
+
+    #[test]
+    fn full_scan() {
+        let dir = tempdir::TempDir::new("index").unwrap();
+        let docs: Vec<Vec<u8>> = (0..FULL_SCAN_LEN + 1)
+            .map(|no| format!("hi {}", no).into_bytes())
+            .collect();
+        let docs: Vec<&[u8]> = docs.iter().map(|doc| &doc[..]).collect();
+        let (idx, poses) = write_index(dir.path(), &docs);
+        let index = Index::open(vec![idx]).unwrap();
+
+        let counting = Arc::new(Counting::default());
+        let mut options = SearchOptions {
+            filter: Some(counting.clone()),
+            ..SearchOptions::default()
+        };
+
+        let found = index.documents_for_search("h", &options).unwrap();
+        assert_eq!(Strategy::FullScan, found.strategy);
+        assert_eq!(FULL_SCAN_LEN as usize, found.docs.len());
+        assert_eq!(FULL_SCAN_LEN as usize, *counting.asked.lock().unwrap());
+        assert!(found.next.is_some());
+
+        options.after = found.next;
+        let found = index.documents_for_search("h", &options).unwrap();
+        let found: Vec<u64> = found.docs.iter().map(|doc| doc.pos).collect();
+        assert_eq!(&poses[FULL_SCAN_LEN as usize..], &found[..]);
+    }
+
+    #[test]
+    fn strings_offsets() {
+        let dir = tempdir::TempDir::new("index").unwrap();
+
+        let mut blob = b"\x7fELF\0\0\0\0first line\n".to_vec();
+        blob.extend(&[0u8; 9]);
+        blob.extend("wide text".bytes().flat_map(|b| vec![b, 0]));
+        blob.extend(&[0u8; 3]);
+        blob.extend(b"last text");
+
+        let (_, poses) = write_index(dir.path(), &[&blob]);
+        let pack = catfight::Pack::open(dir.path().join(PACK)).unwrap();
+        let local = poses[0] - names::addendum_from_path(PACK).unwrap().1;
+
+        let options = SearchOptions::default();
+        let lines = grep_document(&pack, local, 4, &b"text"[..], &options).unwrap();
+        let found: Vec<(u64, u64, &str, &[(usize, usize)])> = lines
+            .iter()
+            .map(|line| (line.line, line.offset, &line.text[..], &line.matches[..]))
+            .collect();
+
+        // after the newline in the first run, and past the padding
+        assert_eq!(
+            vec![
+                (2, 28, "wide text", &[(5, 9)][..]),
+                (2, 49, "last text", &[(5, 9)][..]),
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn corrupt_lists() {
+        let dir = tempdir::TempDir::new("index").unwrap();
+        let (idx, poses) = write_index(dir.path(), &[b"hello world", b"hello again"]);
+
+        let index = Index::open(vec![idx.clone()]).unwrap();
+        let found = index.documents_for_search("hello", &SearchOptions::default());
+        let found: Vec<u64> = found.unwrap().docs.iter().map(|doc| doc.pos).collect();
+        assert_eq!(poses, found);
+        drop(index);
+
+        // claim the list for "hel" is far longer than it is
+        let tri = *tri::Simplified.trigrams("hel").iter().next().unwrap();
+        let mut file = fs::read(&idx).unwrap();
+        let directory = file.len() - directory_len(&tri::Simplified);
+        let at = LittleEndian::read_u64(&file[directory + tri as usize * 8..]) as usize;
+        let at = header::HEADER_LEN + at;
+        LittleEndian::write_u32(&mut file[at..], 0xffff);
+        fs::write(&idx, file).unwrap();
+
+        // opening doesn't read the lists, but anything which does finds out
+        let segment = Segment::open(&idx).unwrap();
+        assert!(segment.verify().is_err());
+        assert!(segment.postings(tri).is_err());
+
+        let index = Index::open(vec![idx]).unwrap();
+        assert!(index.documents_for_tri(tri).is_err());
+        assert!(index
+            .documents_for_search("hello", &SearchOptions::default())
+            .is_err());
+        assert!(index
+            .documents_for_search("world", &SearchOptions::default())
+            .is_ok());
+    }
 
     #[test]
     fn intersection() {
@@ -224,10 +1378,24 @@ mod tests {
         assert_eq!(
             vec![2, 3, 4],
             find_intersection(vec![
-                d1.iter().peekable(),
-                d2.iter().peekable(),
-                d3.iter().peekable(),
+                PostingCursor::ids(&d1),
+                PostingCursor::ids(&d2),
+                PostingCursor::ids(&d3),
             ])
+            .unwrap()
         );
     }
+
+    #[test]
+    fn cursor() {
+        let cursor = Cursor {
+            file: 7,
+            local: 123_456,
+        };
+        assert_eq!("7-123456", cursor.to_string());
+        assert_eq!(cursor, "7-123456".parse().unwrap());
+        assert!("7".parse::<Cursor>().is_err());
+        assert!("7-".parse::<Cursor>().is_err());
+        assert!("x-7".parse::<Cursor>().is_err());
+    }
 }
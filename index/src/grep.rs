@@ -1,8 +1,319 @@
+use aho_corasick::AcAutomaton;
+use aho_corasick::Automaton;
+use regex;
 use twoway;
 
+use query::Expr;
+
+use std::cmp;
 use std::io;
+use std::io::BufRead;
 use std::io::Read;
 
+use std::collections::VecDeque;
+
+/// Something which can be searched for within a line.
+pub trait Matcher {
+    /// Every non-overlapping `(start, end)` match in the `line`.
+    fn find_all(&self, line: &[u8]) -> Vec<(usize, usize)>;
+
+    /// Matchers which care about more than single lines, like a term being absent,
+    /// get to see the whole document, and reject it, before any lines are found.
+    fn wants_document(&self) -> bool {
+        false
+    }
+
+    fn accepts_document(&self, _document: &[u8]) -> bool {
+        true
+    }
+}
+
+impl Matcher for [u8] {
+    fn find_all(&self, line: &[u8]) -> Vec<(usize, usize)> {
+        let mut found = Vec::new();
+        if self.is_empty() {
+            return found;
+        }
+
+        let mut start = 0;
+        while let Some(pos) = twoway::find_bytes(&line[start..], self) {
+            found.push((start + pos, start + pos + self.len()));
+            start += pos + self.len();
+        }
+        found
+    }
+}
+
+/// Matches regardless of ASCII case. This is the same folding the trigram index does;
+/// anything outside of ASCII must match exactly.
+pub struct Folded {
+    needle: Vec<u8>,
+}
+
+impl Folded {
+    pub fn new(needle: &[u8]) -> Folded {
+        Folded {
+            needle: needle.to_ascii_lowercase(),
+        }
+    }
+}
+
+impl Matcher for Folded {
+    fn find_all(&self, line: &[u8]) -> Vec<(usize, usize)> {
+        // lowercasing ASCII doesn't move anything, so the offsets are still valid
+        self.needle[..].find_all(&line.to_ascii_lowercase())
+    }
+}
+
+impl Matcher for regex::bytes::Regex {
+    fn find_all(&self, line: &[u8]) -> Vec<(usize, usize)> {
+        self.find_iter(line)
+            .filter(|m| m.start() != m.end())
+            .map(|m| (m.start(), m.end()))
+            .collect()
+    }
+}
+
+/// Confirms a boolean query: finds all of its terms in one pass over the document,
+/// then checks the right ones were there. Only terms which are wanted are highlighted.
+pub struct Terms {
+    check: Check,
+    folded: bool,
+    all: AcAutomaton<Vec<u8>>,
+    wanted: AcAutomaton<Vec<u8>>,
+}
+
+/// An `Expr`, referring to terms by their pattern number in `Terms::all`.
+enum Check {
+    Term(usize),
+    Not(Box<Check>),
+    And(Vec<Check>),
+    Or(Vec<Check>),
+}
+
+impl Terms {
+    /// Ignoring ASCII case, like `Folded`, if `folded`.
+    pub fn new(expr: &Expr, folded: bool) -> Terms {
+        let mut all = Vec::new();
+        let mut wanted = Vec::new();
+        let check = Terms::compile(expr, folded, false, &mut all, &mut wanted);
+        Terms {
+            check,
+            folded,
+            all: AcAutomaton::new(all),
+            wanted: AcAutomaton::new(wanted),
+        }
+    }
+
+    fn compile(
+        expr: &Expr,
+        folded: bool,
+        negated: bool,
+        all: &mut Vec<Vec<u8>>,
+        wanted: &mut Vec<Vec<u8>>,
+    ) -> Check {
+        match *expr {
+            Expr::Term(ref term) => {
+                let term = if folded {
+                    term.to_ascii_lowercase().into_bytes()
+                } else {
+                    term.as_bytes().to_vec()
+                };
+
+                if !negated && !wanted.contains(&term) {
+                    wanted.push(term.clone());
+                }
+
+                Check::Term(match all.iter().position(|existing| *existing == term) {
+                    Some(pos) => pos,
+                    None => {
+                        all.push(term);
+                        all.len() - 1
+                    }
+                })
+            }
+            Expr::Not(ref expr) => Check::Not(Box::new(Terms::compile(
+                expr, folded, !negated, all, wanted,
+            ))),
+            Expr::And(ref exprs) => Check::And(
+                exprs
+                    .iter()
+                    .map(|expr| Terms::compile(expr, folded, negated, all, wanted))
+                    .collect(),
+            ),
+            Expr::Or(ref exprs) => Check::Or(
+                exprs
+                    .iter()
+                    .map(|expr| Terms::compile(expr, folded, negated, all, wanted))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Terms {
+    /// Whether there's nothing to look for, only things to avoid, so no lines could ever match.
+    pub fn wants_nothing(&self) -> bool {
+        self.wanted.patterns().is_empty()
+    }
+}
+
+impl Check {
+    fn eval(&self, present: &[bool]) -> bool {
+        match *self {
+            Check::Term(pos) => present[pos],
+            Check::Not(ref check) => !check.eval(present),
+            Check::And(ref checks) => checks.iter().all(|check| check.eval(present)),
+            Check::Or(ref checks) => checks.iter().any(|check| check.eval(present)),
+        }
+    }
+}
+
+impl Matcher for Terms {
+    fn find_all(&self, line: &[u8]) -> Vec<(usize, usize)> {
+        let find = |line: &[u8]| -> Vec<(usize, usize)> {
+            self.wanted.find(line).map(|m| (m.start, m.end)).collect()
+        };
+
+        if self.folded {
+            find(&line.to_ascii_lowercase())
+        } else {
+            find(line)
+        }
+    }
+
+    fn wants_document(&self) -> bool {
+        true
+    }
+
+    fn accepts_document(&self, document: &[u8]) -> bool {
+        let mut present = vec![false; self.all.patterns().len()];
+        let mut mark = |document: &[u8]| {
+            for m in self.all.find_overlapping(document) {
+                present[m.pati] = true;
+            }
+        };
+
+        if self.folded {
+            mark(&document.to_ascii_lowercase());
+        } else {
+            mark(document);
+        }
+
+        self.check.eval(&present)
+    }
+}
+
+/// A line containing at least one match, and some of the lines around it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineMatch {
+    /// One-based, like every editor.
+    pub line: u64,
+
+    /// Byte offset of the start of the line within the document.
+    /// For a binary document, the line is a run of text, and this is where the run starts.
+    pub offset: u64,
+
+    /// `(start, end)` byte offsets of each match within the `text`.
+    pub matches: Vec<(usize, usize)>,
+
+    /// The line itself, without its line ending, and lossily converted from whatever it was.
+    /// A long line is cut down to `MAX_LINE_LEN` bytes around its first match.
+    pub text: String,
+
+    /// Context lines, cut down to their first `MAX_LINE_LEN` bytes.
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+/// Lines, like minified code, can be megabytes long, but only a screenful is useful.
+pub const MAX_LINE_LEN: usize = 400;
+
+/// The part of `line`, at most `MAX_LINE_LEN` long, around the first of the `matches`,
+/// with the `matches` in it, moved to be within it.
+fn around(line: &[u8], matches: &[(usize, usize)]) -> (String, Vec<(usize, usize)>) {
+    let start = matches[0].0.saturating_sub(MAX_LINE_LEN / 2);
+    let start = cmp::min(start, line.len().saturating_sub(MAX_LINE_LEN));
+    let end = cmp::min(line.len(), start + MAX_LINE_LEN);
+
+    let matches = matches
+        .iter()
+        .filter(|&&(from, to)| from < end && to > start)
+        .map(|&(from, to)| (cmp::max(from, start) - start, cmp::min(to, end) - start))
+        .collect();
+
+    (
+        String::from_utf8_lossy(&line[start..end]).into_owned(),
+        matches,
+    )
+}
+
+/// Find every line that `matcher` matches, along with up to `context` lines either side.
+/// Like `grep`, matches can't span lines.
+pub fn reader_lines<M: Matcher + ?Sized, R: Read>(
+    matcher: &M,
+    haystack: R,
+    context: usize,
+) -> io::Result<Vec<LineMatch>> {
+    let mut haystack = io::BufReader::new(haystack);
+
+    let mut found: Vec<LineMatch> = Vec::new();
+    let mut before: VecDeque<String> = VecDeque::with_capacity(context + 1);
+
+    let mut buf = Vec::new();
+    let mut line = 0u64;
+    let mut offset = 0u64;
+
+    loop {
+        buf.clear();
+        let read = haystack.read_until(b'\n', &mut buf)?;
+        if 0 == read {
+            break;
+        }
+
+        line += 1;
+
+        let mut end = buf.len();
+        while end > 0 && (b'\n' == buf[end - 1] || b'\r' == buf[end - 1]) {
+            end -= 1;
+        }
+
+        let text = String::from_utf8_lossy(&buf[..cmp::min(end, MAX_LINE_LEN)]).into_owned();
+
+        // earlier matches may still want this line as their trailing context
+        for prev in found.iter_mut().rev() {
+            if prev.line + (context as u64) < line {
+                break;
+            }
+            prev.after.push(text.clone());
+        }
+
+        let matches = matcher.find_all(&buf[..end]);
+        if !matches.is_empty() {
+            let (text, matches) = around(&buf[..end], &matches);
+            found.push(LineMatch {
+                line,
+                offset,
+                matches,
+                text,
+                before: before.iter().cloned().collect(),
+                after: Vec::with_capacity(context),
+            });
+        }
+
+        if context > 0 {
+            if before.len() == context {
+                before.pop_front();
+            }
+            before.push_back(text);
+        }
+
+        offset += read as u64;
+    }
+
+    Ok(found)
+}
+
 /// fooBARbaz
 /// search: BAR
 /// block size: 4
@@ -10,10 +321,12 @@ use std::io::Read;
 /// retain 2 characters?
 /// "oBAR" -> 2, plus 4 - 2 -> 4?
 /// Worst example ever.
-pub fn reader_contains<R: Read>(needle: &[u8], haystack: R) -> io::Result<Option<u64>> {
+#[cfg(test)]
+fn reader_contains<R: Read>(needle: &[u8], haystack: R) -> io::Result<Option<u64>> {
     reader_contains_external_buf(needle, haystack, &mut [0u8; 16 * 1024])
 }
 
+#[cfg(test)]
 #[inline]
 fn reader_contains_external_buf<R: Read>(
     needle: &[u8],
@@ -59,9 +372,14 @@ fn reader_contains_external_buf<R: Read>(
 
 #[cfg(test)]
 mod tests {
-    use std::io;
     use super::reader_contains;
     use super::reader_contains_external_buf;
+    use super::reader_lines;
+    use super::Folded;
+    use super::Matcher;
+    use super::Terms;
+    use super::MAX_LINE_LEN;
+    use std::io;
     const MSG: &str = "Inches aren't very granular.";
 
     #[test]
@@ -108,6 +426,75 @@ mod tests {
         }
     }
 
+    #[test]
+    fn lines() {
+        let doc = "one\ntwo foo\nthree\r\nfour foo foo\nfive\nsix\n";
+        let found = reader_lines("foo".as_bytes(), cursor(doc), 1).unwrap();
+        assert_eq!(2, found.len());
+
+        assert_eq!(2, found[0].line);
+        assert_eq!(4, found[0].offset);
+        assert_eq!(vec![(4, 7)], found[0].matches);
+        assert_eq!("two foo", found[0].text);
+        assert_eq!(vec!["one".to_string()], found[0].before);
+        assert_eq!(vec!["three".to_string()], found[0].after);
+
+        assert_eq!(4, found[1].line);
+        assert_eq!(19, found[1].offset);
+        assert_eq!(vec![(5, 8), (9, 12)], found[1].matches);
+        assert_eq!(vec!["three".to_string()], found[1].before);
+        assert_eq!(vec!["five".to_string()], found[1].after);
+
+        let found = reader_lines("foo".as_bytes(), cursor(doc), 0).unwrap();
+        assert!(found[0].before.is_empty());
+        assert!(found[1].after.is_empty());
+
+        assert!(reader_lines("bar".as_bytes(), cursor(doc), 3)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn long_lines() {
+        let long = format!("{}foo{}", "x".repeat(1000), "y".repeat(1000));
+        let doc = format!("{}\n{}\n{}\n", long, long, "foo ".repeat(1000));
+        let found = reader_lines("foo".as_bytes(), cursor(&doc), 1).unwrap();
+        assert_eq!(3, found.len());
+
+        let m = &found[0];
+        assert_eq!(MAX_LINE_LEN, m.text.len());
+        assert_eq!(vec![(MAX_LINE_LEN / 2, MAX_LINE_LEN / 2 + 3)], m.matches);
+        assert_eq!("foo", &m.text[m.matches[0].0..m.matches[0].1]);
+        assert_eq!(MAX_LINE_LEN, m.after[0].len());
+        assert!(m.after[0].starts_with("xxx"));
+
+        // only the matches in the window
+        let m = &found[2];
+        assert_eq!(MAX_LINE_LEN, m.text.len());
+        assert_eq!(MAX_LINE_LEN / 4, m.matches.len());
+        assert_eq!(
+            Some(&(MAX_LINE_LEN - 4, MAX_LINE_LEN - 1)),
+            m.matches.last()
+        );
+    }
+
+    #[test]
+    fn folded() {
+        let doc = "Foo\nfOO bar\nfo\u{f6}\nFO\u{d6}\n";
+        let found = reader_lines(&Folded::new(b"FOO"), cursor(doc), 0).unwrap();
+        assert_eq!(
+            vec![(1, vec![(0, 3)]), (2, vec![(0, 3)])],
+            found
+                .into_iter()
+                .map(|m| (m.line, m.matches))
+                .collect::<Vec<(u64, Vec<(usize, usize)>)>>()
+        );
+
+        let found = reader_lines(&Folded::new("fo\u{f6}".as_bytes()), cursor(doc), 0).unwrap();
+        assert_eq!(1, found.len());
+        assert_eq!(3, found[0].line);
+    }
+
     fn zero(buf: &mut [u8]) {
         for i in 0..buf.len() {
             buf[i] = 0;
@@ -117,4 +504,28 @@ mod tests {
     fn cursor(s: &str) -> io::Cursor<&str> {
         io::Cursor::new(s)
     }
+
+    #[test]
+    fn terms() {
+        use query::parse;
+        let terms = |q: &str, folded: bool| Terms::new(&parse(q).unwrap().expr.unwrap(), folded);
+
+        let doc = b"let foo = 5;\nbar(Foo);\n";
+        assert!(terms("foo bar", false).accepts_document(doc));
+        assert!(!terms("foo -bar", false).accepts_document(doc));
+        assert!(terms("foo -baz", false).accepts_document(doc));
+        assert!(terms("baz OR bar", false).accepts_document(doc));
+        assert!(!terms("baz OR quux", false).accepts_document(doc));
+        assert!(!terms("FOO", false).accepts_document(doc));
+        assert!(terms("FOO", true).accepts_document(doc));
+
+        let found = reader_lines(&terms("foo -baz OR bar", true), &doc[..], 0).unwrap();
+        assert_eq!(
+            vec![(1, vec![(4, 7)]), (2, vec![(0, 3), (4, 7)])],
+            found
+                .into_iter()
+                .map(|m| (m.line, m.matches))
+                .collect::<Vec<(u64, Vec<(usize, usize)>)>>()
+        );
+    }
 }
@@ -0,0 +1,372 @@
+//! The header at the start of every `.idx` file, saying what it is, and what it's an index of.
+//!
+//! ```text
+//! 0   magic     b"deb2pgix"
+//! 8   version   u32, `VERSION`
+//! 12  scheme    u32, how text was turned into trigrams, as in `tri::scheme`
+//! 16  addendum  u64, added to local document ids to make a `pos`
+//! 24  pack_len  u64, how much of the pack was indexed
+//! 32  checksum  u64, of everything after the header and pack table
+//! 40  pack      the pack's file name, in the same directory, zero padded
+//! 104 start     u64, where in the pack indexing started; later segments carry on from earlier ones
+//! 112 strings   u32, if not zero, only runs of this many printable characters were indexed,
+//!               as in `strings`; otherwise whole documents were
+//! 116 packs     u32, how many packs are indexed; only a merged index has more than one
+//! 120 format    u32, how every posting list is encoded: `find::FORMAT_PACKED`; a merged index's
+//!               lists have u64 ids
+//! 124 reserved, zero
+//! ```
+//!
+//! The addendum, pack_len, pack and start are of the first pack. Any others follow the header,
+//! in the pack table, as:
+//!
+//! ```text
+//! 0   addendum  u64
+//! 8   start     u64
+//! 16  pack_len  u64
+//! 24  pack      zero padded
+//! 88  reserved, zero
+//! ```
+//!
+//! Everything is little endian. After the header come the posting lists, then the directory
+//! of where each trigram's list is, as read by `find::Segment`.
+
+use std::io;
+use std::str;
+
+use std::io::Read;
+
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use errors::*;
+
+pub const MAGIC: &[u8; 8] = b"deb2pgix";
+
+/// Files from before the header were just blocks of raw lists; they, and any other version,
+/// have to be rebuilt.
+pub const VERSION: u32 = 5;
+
+/// `tri::simplify`: case folded, and squashed into 64 symbols.
+pub const SCHEME_SIMPLIFIED: u32 = 1;
+
+/// `tri::Bytes`: UTF-8 bytes, ASCII case folded, with trigrams hashed to 2^21.
+pub const SCHEME_BYTES: u32 = 2;
+
+pub const HEADER_LEN: usize = 128;
+
+/// Where the first record in a pack is, after the pack's own header.
+pub const PACK_START: u64 = 16;
+
+const PACK_NAME_START: usize = 40;
+const PACK_NAME_LEN: usize = 64;
+
+const PACK_ENTRY_LEN: usize = 96;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub scheme: u32,
+    pub checksum: u64,
+    pub strings: u32,
+    pub format: u32,
+
+    /// Which part of which packs are indexed. A merged index has many, and the top 32 bits
+    /// of each of its document ids say which one the document is in.
+    pub packs: Vec<PackRange>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackRange {
+    /// The pack's file name, in the same directory as the index.
+    pub name: String,
+    pub addendum: u64,
+    pub start: u64,
+    pub pack_len: u64,
+}
+
+impl Header {
+    /// How long the header, and its pack table, are; the lists start after this.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + (self.packs.len() - 1) * PACK_ENTRY_LEN
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        ensure!(!self.packs.is_empty(), "an index has to be of some pack");
+        for pack in &self.packs {
+            ensure!(
+                pack.name.len() <= PACK_NAME_LEN && !pack.name.contains('\0'),
+                "pack name can't be stored in an index: {:?}",
+                pack.name
+            );
+        }
+
+        let mut bytes = vec![0u8; self.encoded_len()];
+        bytes[..8].copy_from_slice(MAGIC);
+        LittleEndian::write_u32(&mut bytes[8..12], VERSION);
+        LittleEndian::write_u32(&mut bytes[12..16], self.scheme);
+        LittleEndian::write_u64(&mut bytes[32..40], self.checksum);
+        LittleEndian::write_u32(&mut bytes[112..116], self.strings);
+        LittleEndian::write_u32(&mut bytes[116..120], self.packs.len() as u32);
+        LittleEndian::write_u32(&mut bytes[120..124], self.format);
+
+        let first = &self.packs[0];
+        LittleEndian::write_u64(&mut bytes[16..24], first.addendum);
+        LittleEndian::write_u64(&mut bytes[24..32], first.pack_len);
+        bytes[PACK_NAME_START..PACK_NAME_START + first.name.len()]
+            .copy_from_slice(first.name.as_bytes());
+        LittleEndian::write_u64(&mut bytes[104..112], first.start);
+
+        for (pack, entry) in self.packs[1..]
+            .iter()
+            .zip(bytes[HEADER_LEN..].chunks_mut(PACK_ENTRY_LEN))
+        {
+            LittleEndian::write_u64(&mut entry[0..8], pack.addendum);
+            LittleEndian::write_u64(&mut entry[8..16], pack.start);
+            LittleEndian::write_u64(&mut entry[16..24], pack.pack_len);
+            entry[24..24 + pack.name.len()].copy_from_slice(pack.name.as_bytes());
+        }
+
+        Ok(bytes)
+    }
+
+    /// Read the header, and pack table, from the start of a file.
+    pub fn read<R: Read>(mut from: R) -> Result<Header> {
+        let mut bytes = vec![0u8; HEADER_LEN];
+        from.read_exact(&mut bytes)?;
+
+        let count = LittleEndian::read_u32(&bytes[116..120]) as u64;
+        let table = count.saturating_sub(1) * PACK_ENTRY_LEN as u64;
+        from.take(table).read_to_end(&mut bytes)?;
+
+        Header::from_bytes(&bytes)
+    }
+
+    /// Read the header, and pack table, from the start of `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Header> {
+        if bytes.len() < HEADER_LEN || &bytes[..8] != MAGIC {
+            bail!(ErrorKind::BadIndex(
+                "no header; it's not an index, or it's from an older reindex, so rebuild it"
+                    .to_string()
+            ));
+        }
+
+        let version = LittleEndian::read_u32(&bytes[8..12]);
+        if VERSION != version {
+            bail!(ErrorKind::BadIndex(format!(
+                "version {} isn't supported, only {}; rebuild it",
+                version, VERSION
+            )));
+        }
+
+        let count = LittleEndian::read_u32(&bytes[116..120]) as usize;
+
+        if 0 == count {
+            bail!(ErrorKind::BadIndex("no packs".to_string()));
+        }
+
+        let table = match bytes.get(HEADER_LEN..HEADER_LEN + (count - 1) * PACK_ENTRY_LEN) {
+            Some(table) => table,
+            None => bail!(ErrorKind::BadIndex("truncated pack table".to_string())),
+        };
+
+        let mut packs = Vec::with_capacity(count);
+        packs.push(read_pack(
+            &bytes[PACK_NAME_START..PACK_NAME_START + PACK_NAME_LEN],
+            LittleEndian::read_u64(&bytes[16..24]),
+            LittleEndian::read_u64(&bytes[104..112]),
+            LittleEndian::read_u64(&bytes[24..32]),
+        )?);
+
+        for entry in table.chunks(PACK_ENTRY_LEN) {
+            packs.push(read_pack(
+                &entry[24..24 + PACK_NAME_LEN],
+                LittleEndian::read_u64(&entry[0..8]),
+                LittleEndian::read_u64(&entry[8..16]),
+                LittleEndian::read_u64(&entry[16..24]),
+            )?);
+        }
+
+        Ok(Header {
+            scheme: LittleEndian::read_u32(&bytes[12..16]),
+            checksum: LittleEndian::read_u64(&bytes[32..40]),
+            strings: LittleEndian::read_u32(&bytes[112..116]),
+            format: LittleEndian::read_u32(&bytes[120..124]),
+            packs,
+        })
+    }
+}
+
+fn read_pack(name: &[u8], addendum: u64, start: u64, pack_len: u64) -> Result<PackRange> {
+    let name_len = name.iter().position(|&b| 0 == b).unwrap_or(name.len());
+    let name = match str::from_utf8(&name[..name_len]) {
+        Ok(name) if !name.is_empty() => name.to_string(),
+        _ => bail!(ErrorKind::BadIndex("invalid pack name".to_string())),
+    };
+
+    if start < PACK_START || start > pack_len {
+        bail!(ErrorKind::BadIndex(format!(
+            "covers {}..{} of {}, which is nonsense",
+            start, pack_len, name
+        )));
+    }
+
+    Ok(PackRange {
+        name,
+        addendum,
+        start,
+        pack_len,
+    })
+}
+
+/// 64-bit FNV-1a.
+#[derive(Clone, Debug)]
+pub struct Checksum {
+    state: u64,
+}
+
+impl Default for Checksum {
+    fn default() -> Checksum {
+        Checksum {
+            state: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+}
+
+impl Checksum {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.state ^= b as u64;
+            self.state = self.state.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.state
+    }
+}
+
+/// Checksums everything written through it.
+pub struct ChecksumWriter<W> {
+    inner: W,
+    pub checksum: Checksum,
+}
+
+impl<W: io::Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            inner,
+            checksum: Checksum::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: io::Write> io::Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use find;
+
+    fn pack(name: &str, addendum: u64, start: u64, pack_len: u64) -> PackRange {
+        PackRange {
+            name: name.to_string(),
+            addendum,
+            start,
+            pack_len,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut header = Header {
+            scheme: SCHEME_SIMPLIFIED,
+            checksum: 0xdead_beef,
+            strings: 4,
+            format: find::FORMAT_PACKED,
+            packs: vec![pack(
+                "text-5.0000000000000000000017",
+                17 * 1024 * 1024 * 1024 + 11,
+                1024,
+                123_456,
+            )],
+        };
+
+        let bytes = header.to_bytes().unwrap();
+        assert_eq!(HEADER_LEN, bytes.len());
+        assert_eq!(header, Header::from_bytes(&bytes).unwrap());
+
+        header
+            .packs
+            .push(pack("text-5.0000000000000000000018", 18, 16, 1_000));
+        header
+            .packs
+            .push(pack("text-5.0000000000000000000019", 19, 16, 2_000));
+
+        let bytes = header.to_bytes().unwrap();
+        assert_eq!(header.encoded_len(), bytes.len());
+        assert_eq!(header, Header::from_bytes(&bytes).unwrap());
+        assert!(Header::from_bytes(&bytes[..HEADER_LEN]).is_err());
+
+        let mut file = bytes.clone();
+        file.extend(&[0xD8, 0x1F]);
+        assert_eq!(header, Header::read(io::Cursor::new(file)).unwrap());
+    }
+
+    #[test]
+    fn rejected() {
+        assert!(Header::from_bytes(b"").is_err());
+        assert!(Header::from_bytes(&[0u8; HEADER_LEN]).is_err());
+
+        let mut header = Header {
+            scheme: SCHEME_SIMPLIFIED,
+            checksum: 0,
+            strings: 0,
+            format: find::FORMAT_PACKED,
+            packs: vec![pack(
+                "bin-2.0000000000000000000000",
+                0,
+                PACK_START,
+                PACK_START,
+            )],
+        };
+
+        let mut bytes = header.to_bytes().unwrap();
+        for &version in &[2, 4, 6] {
+            bytes[8] = version;
+            assert!(Header::from_bytes(&bytes).is_err());
+        }
+
+        bytes[8] = VERSION as u8;
+        bytes[116] = 0;
+        assert!(Header::from_bytes(&bytes).is_err());
+
+        header.packs[0].start = PACK_START + 16;
+        assert!(Header::from_bytes(&header.to_bytes().unwrap()).is_err());
+
+        header.packs[0].name = "x".repeat(65);
+        assert!(header.to_bytes().is_err());
+    }
+
+    #[test]
+    fn checksum() {
+        // the published test vectors
+        assert_eq!(0xcbf2_9ce4_8422_2325, Checksum::default().value());
+        let mut checksum = Checksum::default();
+        checksum.update(b"a");
+        assert_eq!(0xaf63_dc4c_8601_ec8c, checksum.value());
+    }
+}
@@ -1,3 +1,4 @@
+extern crate aho_corasick;
 extern crate bit_set;
 extern crate byteorder;
 extern crate catfight;
@@ -6,15 +7,23 @@ extern crate error_chain;
 extern crate libc;
 extern crate lz4;
 extern crate memmap;
+extern crate rayon;
+extern crate regex;
 extern crate regex_syntax;
 extern crate twoway;
 
 pub mod find;
 mod grep;
+pub mod header;
 pub mod names;
+mod plan;
+pub mod postings;
+pub mod query;
 mod shards;
-mod tri;
+pub mod strings;
+pub mod tri;
 
+pub use grep::LineMatch;
 pub use tri::trigrams_full;
 pub use tri::explain_packed;
 
@@ -24,11 +33,25 @@ pub use errors::*;
 
 mod errors {
     error_chain! {
+        errors {
+            InvalidQuery(msg: String) {
+                description("invalid query")
+                display("invalid query: {}", msg)
+            }
+
+            BadIndex(msg: String) {
+                description("unusable index file")
+                display("unusable index file: {}", msg)
+            }
+        }
+
         links {
             Catfight(::catfight::Error, ::catfight::ErrorKind);
         }
         foreign_links {
             Io(::std::io::Error);
+            Regex(::regex::Error);
+            RegexSyntax(::regex_syntax::Error);
         }
     }
 }
@@ -1,5 +1,7 @@
 use std::cmp::{max, min};
 
+use errors::*;
+
 const BLOCK_SIZE: u64 = 1024 * 1024 * 1024;
 const MIN_SHARD_NO: u8 = 2;
 const SHARD_NO_TEXT_OFFSET: u8 = 8;
@@ -64,34 +66,48 @@ pub fn filename_for(pos: u64) -> (String, u32) {
     (file_name, file_pos)
 }
 
-// TODO: this is awful
-pub fn addendum_from_path(path: &str) -> (u8, u64) {
-    // text-5.0000000000000000000000.idx
-    let text = if path.starts_with("text-") {
-        true
+/// Whether a pack (or anything named after it) holds documents which didn't look like text.
+pub fn is_binary_pack(path: &str) -> bool {
+    path.starts_with("bin-")
+}
+
+/// The shard's size number, and the value added to local document ids to make a `pos`,
+/// for a pack (or anything named after it) like `text-5.0000000000000000000017`.
+pub fn addendum_from_path(path: &str) -> Result<(u8, u64)> {
+    let (text, rest) = if path.starts_with("text-") {
+        (true, &path[5..])
     } else if path.starts_with("bin-") {
-        false
+        (false, &path[4..])
     } else {
-        panic!("path must start with 'text-' or 'bin-', not {}", path);
+        bail!("pack name must start with 'text-' or 'bin-', not {:?}", path);
+    };
+
+    let mut parts = rest.splitn(2, '.');
+
+    let size = match parts.next().and_then(|size| size.parse::<u8>().ok()) {
+        Some(size) if size >= MIN_SHARD_NO && size <= 9 => size,
+        _ => bail!("pack name has no shard size: {:?}", path),
+    };
+
+    // the digits of the chunk number, ignoring any extension after them
+    let chunk = parts.next().unwrap_or("");
+    let digits = chunk
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(chunk.len());
+
+    let chunk = match chunk[..digits].parse::<u64>() {
+        Ok(chunk) => chunk,
+        Err(_) => bail!("pack name has no chunk number: {:?}", path),
     };
 
-    let mut it = path.chars().skip(if text { 5 } else { 4 });
-    let size_raw = it.next().expect("num") as u8 - '2' as u8;
-    assert!(size_raw >= 2 - 2 && size_raw <= 9 - 2);
-    assert_eq!('.', it.next().unwrap());
-
-    (
-        size_raw + 2,
-        // TODO: NO JUST NO WHY
-        it.take("0000000000000000000000".len())
-            .collect::<String>()
-            .parse::<u64>()
-            .expect("second num") * 1024 * 1024 * 1024 + (size_raw as u64) + if text {
-            8
+    Ok((
+        size,
+        chunk * BLOCK_SIZE + (size - MIN_SHARD_NO) as u64 + if text {
+            SHARD_NO_TEXT_OFFSET as u64
         } else {
             0
         },
-    )
+    ))
 }
 
 #[cfg(test)]
@@ -184,20 +200,41 @@ mod tests {
 
     #[test]
     fn from_path() {
-        assert_eq!((2, 0), addendum_from_path("bin-2.0000000000000000000000"));
-        assert_eq!((2, 8), addendum_from_path("text-2.0000000000000000000000"));
+        assert_eq!(
+            (2, 0),
+            addendum_from_path("bin-2.0000000000000000000000").unwrap()
+        );
+        assert_eq!(
+            (2, 8),
+            addendum_from_path("text-2.0000000000000000000000").unwrap()
+        );
         assert_eq!(
             (2, 17 * 1024 * 1024 * 1024),
-            addendum_from_path("bin-2.0000000000000000000017")
+            addendum_from_path("bin-2.0000000000000000000017").unwrap()
         );
         assert_eq!(
             (2, 17 * 1024 * 1024 * 1024 + 8),
-            addendum_from_path("text-2.0000000000000000000017")
+            addendum_from_path("text-2.0000000000000000000017").unwrap()
         );
 
         assert_eq!(
             (3, 17 * 1024 * 1024 * 1024 + 9),
-            addendum_from_path("text-3.0000000000000000000017")
+            addendum_from_path("text-3.0000000000000000000017").unwrap()
         );
+
+        assert_eq!(
+            (5, 3 * 1024 * 1024 * 1024 + 11),
+            addendum_from_path("text-5.0000000003.cfp.idx").unwrap()
+        );
+    }
+
+    #[test]
+    fn bad_path() {
+        assert!(addendum_from_path("").is_err());
+        assert!(addendum_from_path("foo-2.0000000000").is_err());
+        assert!(addendum_from_path("text-1.0000000000").is_err());
+        assert!(addendum_from_path("text-x.0000000000").is_err());
+        assert!(addendum_from_path("text-2").is_err());
+        assert!(addendum_from_path("text-2.idx").is_err());
     }
 }
@@ -0,0 +1,826 @@
+//! Turn a regex into a boolean query over trigrams, in the style of Russ Cox's codesearch:
+//! https://swtch.com/~rsc/regexp/regexp4.html
+//!
+//! Everything here works on a `tri::Scheme`'s symbols, not on chars, so `[aA]` is a single symbol,
+//! and the generated trigrams are exactly the ones `reindex` would have recorded.
+
+use std::mem;
+
+use std::collections::BTreeSet;
+
+use regex_syntax::hir;
+use regex_syntax::hir::Hir;
+use regex_syntax::hir::HirKind;
+
+use query::Expr;
+use tri::Scheme;
+
+/// A condition on the trigrams present in a document, which any matching document must satisfy.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TriQuery {
+    /// The index can't narrow anything down; every document is a candidate.
+    All,
+    /// No document can match.
+    Nothing,
+    Tri(u32),
+    And(Vec<TriQuery>),
+    Or(Vec<TriQuery>),
+}
+
+impl TriQuery {
+    pub fn and(self, other: TriQuery) -> TriQuery {
+        match (self, other) {
+            (TriQuery::All, x) | (x, TriQuery::All) => x,
+            (TriQuery::Nothing, _) | (_, TriQuery::Nothing) => TriQuery::Nothing,
+            (TriQuery::And(mut left), TriQuery::And(right)) => {
+                for item in right {
+                    push_unique(&mut left, item);
+                }
+                TriQuery::And(left)
+            }
+            (TriQuery::And(mut list), x) | (x, TriQuery::And(mut list)) => {
+                push_unique(&mut list, x);
+                TriQuery::And(list)
+            }
+            (left, right) => {
+                if left == right {
+                    left
+                } else {
+                    TriQuery::And(sorted_pair(left, right))
+                }
+            }
+        }
+    }
+
+    pub fn or(self, other: TriQuery) -> TriQuery {
+        match (self, other) {
+            (TriQuery::All, _) | (_, TriQuery::All) => TriQuery::All,
+            (TriQuery::Nothing, x) | (x, TriQuery::Nothing) => x,
+            (TriQuery::Or(mut left), TriQuery::Or(right)) => {
+                for item in right {
+                    push_unique(&mut left, item);
+                }
+                TriQuery::Or(left)
+            }
+            (TriQuery::Or(mut list), x) | (x, TriQuery::Or(mut list)) => {
+                push_unique(&mut list, x);
+                TriQuery::Or(list)
+            }
+            (left, right) => {
+                if left == right {
+                    left
+                } else {
+                    TriQuery::Or(sorted_pair(left, right))
+                }
+            }
+        }
+    }
+}
+
+fn sorted_pair(left: TriQuery, right: TriQuery) -> Vec<TriQuery> {
+    if left < right {
+        vec![left, right]
+    } else {
+        vec![right, left]
+    }
+}
+
+/// Keep the list sorted, so equivalent queries compare equal.
+fn push_unique(list: &mut Vec<TriQuery>, item: TriQuery) {
+    if let Err(pos) = list.binary_search(&item) {
+        list.insert(pos, item);
+    }
+}
+
+/// The query for a document containing `input` somewhere.
+pub fn from_literal(scheme: &Scheme, input: &str) -> TriQuery {
+    trigrams_of(scheme, &scheme.symbols(input))
+}
+
+/// The query for a document containing `input`, even if it's only two symbols long,
+/// by looking for every trigram which starts or ends with them. This misses documents
+/// which are *only* those two symbols, as they have no trigrams at all.
+pub fn from_short_literal(scheme: &Scheme, input: &str) -> TriQuery {
+    let symbols = scheme.symbols(input);
+    if 2 != symbols.len() {
+        return trigrams_of(scheme, &symbols);
+    }
+
+    (0..scheme.alphabet()).fold(TriQuery::Nothing, |query, other| {
+        let other = other as u8;
+        query
+            .or(TriQuery::Tri(scheme.pack([other, symbols[0], symbols[1]])))
+            .or(TriQuery::Tri(scheme.pack([symbols[0], symbols[1], other])))
+    })
+}
+
+/// The query for a document which could satisfy `expr`. Terms which must be absent
+/// don't tell us anything; they can only be checked by reading the document.
+pub fn from_expr(scheme: &Scheme, expr: &Expr) -> TriQuery {
+    from_expr_with(scheme, expr, &from_literal)
+}
+
+/// Like `from_expr`, but expanding two-symbol terms with `from_short_literal`.
+pub fn from_expr_expanded(scheme: &Scheme, expr: &Expr) -> TriQuery {
+    from_expr_with(scheme, expr, &from_short_literal)
+}
+
+fn from_expr_with(
+    scheme: &Scheme,
+    expr: &Expr,
+    literal: &Fn(&Scheme, &str) -> TriQuery,
+) -> TriQuery {
+    match *expr {
+        Expr::Term(ref term) => literal(scheme, term),
+        Expr::Not(_) => TriQuery::All,
+        Expr::And(ref exprs) => exprs.iter().fold(TriQuery::All, |query, expr| {
+            query.and(from_expr_with(scheme, expr, literal))
+        }),
+        Expr::Or(ref exprs) => exprs.iter().fold(TriQuery::Nothing, |query, expr| {
+            query.or(from_expr_with(scheme, expr, literal))
+        }),
+    }
+}
+
+/// The query for a document which `hir` could match against.
+pub fn from_regex(scheme: &Scheme, hir: &Hir) -> TriQuery {
+    let mut info = analyse(scheme, hir);
+    info.simplify(scheme, true);
+    info.add_exact(scheme);
+    info.query
+}
+
+/// Whether every match of `hir` has a newline in it, like `foo\nbar`, or it has a class of every
+/// character, like `.` after `(?s)`, which was probably meant to match across lines. Each line is
+/// grepped on its own, so neither does what was meant. Classes like `\s` or `[^x]` also match a
+/// newline, but they're allowed, as they match other things within a line.
+pub fn spans_lines(hir: &Hir) -> bool {
+    needs_newline(hir) || matches_anything(hir)
+}
+
+/// Whether every match of `hir` has a newline in it.
+fn needs_newline(hir: &Hir) -> bool {
+    use regex_syntax::hir::RepetitionKind::*;
+    use regex_syntax::hir::RepetitionRange::*;
+
+    match *hir.kind() {
+        HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => false,
+        HirKind::Literal(hir::Literal::Unicode(c)) => '\n' == c,
+        HirKind::Literal(hir::Literal::Byte(b)) => b'\n' == b,
+        HirKind::Class(hir::Class::Unicode(ref class)) => class
+            .iter()
+            .all(|range| '\n' == range.start() && '\n' == range.end()),
+        HirKind::Class(hir::Class::Bytes(ref class)) => class
+            .iter()
+            .all(|range| b'\n' == range.start() && b'\n' == range.end()),
+        HirKind::Group(ref group) => needs_newline(&group.hir),
+        HirKind::Repetition(ref rep) => match rep.kind {
+            ZeroOrOne | ZeroOrMore => false,
+            OneOrMore => needs_newline(&rep.hir),
+            Range(Exactly(n)) | Range(AtLeast(n)) | Range(Bounded(n, _)) => {
+                0 != n && needs_newline(&rep.hir)
+            }
+        },
+        HirKind::Concat(ref parts) => parts.iter().any(needs_newline),
+        HirKind::Alternation(ref parts) => parts.iter().all(needs_newline),
+    }
+}
+
+/// Whether `hir` has a class of every character, or every byte, anywhere in it.
+fn matches_anything(hir: &Hir) -> bool {
+    match *hir.kind() {
+        HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) | HirKind::Literal(_) => {
+            false
+        }
+        HirKind::Class(hir::Class::Unicode(ref class)) => class
+            .iter()
+            .any(|range| '\0' == range.start() && '\u{10ffff}' == range.end()),
+        HirKind::Class(hir::Class::Bytes(ref class)) => class
+            .iter()
+            .any(|range| 0 == range.start() && 0xff == range.end()),
+        HirKind::Group(ref group) => matches_anything(&group.hir),
+        HirKind::Repetition(ref rep) => matches_anything(&rep.hir),
+        HirKind::Concat(ref parts) | HirKind::Alternation(ref parts) => {
+            parts.iter().any(matches_anything)
+        }
+    }
+}
+
+/// Sets of strings (of symbols) larger than this are thrown away, after
+/// their trigrams have been moved into the query.
+const MAX_EXACT: usize = 7;
+const MAX_SET: usize = 20;
+
+/// Classes matching more strings of symbols than this aren't worth tracking.
+const MAX_CLASS: usize = 16;
+
+type Set = BTreeSet<Vec<u8>>;
+
+/// What we know about the strings a (sub-)expression can match.
+struct Info {
+    can_empty: bool,
+
+    /// The exact set of strings matched, if it's small enough to track.
+    exact: Option<Set>,
+
+    /// If `exact` is `None`: every match starts with one of these.
+    prefix: Set,
+
+    /// If `exact` is `None`: every match ends with one of these.
+    suffix: Set,
+
+    /// Must be satisfied by any document containing a match.
+    query: TriQuery,
+}
+
+impl Info {
+    fn any_char() -> Info {
+        Info {
+            can_empty: false,
+            exact: None,
+            prefix: single(Vec::new()),
+            suffix: single(Vec::new()),
+            query: TriQuery::All,
+        }
+    }
+
+    fn any_match() -> Info {
+        Info {
+            can_empty: true,
+            ..Info::any_char()
+        }
+    }
+
+    fn empty_string() -> Info {
+        Info {
+            can_empty: true,
+            exact: Some(single(Vec::new())),
+            prefix: Set::new(),
+            suffix: Set::new(),
+            query: TriQuery::All,
+        }
+    }
+
+    fn no_match() -> Info {
+        Info {
+            can_empty: false,
+            exact: Some(Set::new()),
+            prefix: Set::new(),
+            suffix: Set::new(),
+            query: TriQuery::Nothing,
+        }
+    }
+
+    /// A single char, which could be any of these strings of symbols.
+    fn strings(strings: Set) -> Info {
+        if strings.is_empty() {
+            return Info::no_match();
+        }
+
+        if strings.len() > MAX_CLASS {
+            return Info::any_char();
+        }
+
+        Info {
+            exact: Some(strings),
+            ..Info::empty_string()
+        }
+    }
+
+    fn prefixes(&self) -> &Set {
+        self.exact.as_ref().unwrap_or(&self.prefix)
+    }
+
+    fn suffixes(&self) -> &Set {
+        self.exact.as_ref().unwrap_or(&self.suffix)
+    }
+
+    fn add_exact(&mut self, scheme: &Scheme) {
+        if let Some(ref exact) = self.exact {
+            let query = mem::replace(&mut self.query, TriQuery::All);
+            self.query = and_trigrams(scheme, query, exact);
+        }
+    }
+
+    /// Move information out of over-large or over-long sets, and into the query.
+    fn simplify(&mut self, scheme: &Scheme, force: bool) {
+        let convert = match self.exact {
+            Some(ref exact) => {
+                let min = min_len(exact);
+                exact.len() > MAX_EXACT || (force && min >= 3) || min >= 4
+            }
+            None => false,
+        };
+
+        if convert {
+            self.add_exact(scheme);
+            for s in self.exact.take().expect("just checked") {
+                if s.len() < 3 {
+                    self.prefix.insert(s.clone());
+                    self.suffix.insert(s);
+                } else {
+                    self.prefix.insert(s[..2].to_vec());
+                    self.suffix.insert(s[s.len() - 2..].to_vec());
+                }
+            }
+        }
+
+        if self.exact.is_none() {
+            let query = mem::replace(&mut self.query, TriQuery::All);
+            let query = simplify_set(scheme, &mut self.prefix, query, false);
+            self.query = simplify_set(scheme, &mut self.suffix, query, true);
+        }
+    }
+}
+
+fn analyse(scheme: &Scheme, hir: &Hir) -> Info {
+    let mut info = match *hir.kind() {
+        HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => Info::empty_string(),
+        HirKind::Literal(hir::Literal::Unicode(c)) => {
+            Info::strings(single(char_symbols(scheme, c)))
+        }
+        HirKind::Literal(hir::Literal::Byte(b)) => {
+            if b < 0x80 {
+                Info::strings(single(char_symbols(scheme, b as char)))
+            } else if scheme.bytewise() {
+                Info::strings(single(byte_symbols(scheme, b)))
+            } else {
+                Info::any_char()
+            }
+        }
+        HirKind::Class(hir::Class::Unicode(ref class)) => {
+            let mut strings = Set::new();
+            for range in class.iter() {
+                if !scheme.class(range.start(), range.end(), &mut strings, MAX_CLASS) {
+                    return Info::any_char();
+                }
+            }
+            Info::strings(strings)
+        }
+        HirKind::Class(hir::Class::Bytes(ref class)) => {
+            let mut strings = Set::new();
+            for range in class.iter() {
+                if !add_bytes(scheme, &mut strings, range.start(), range.end()) {
+                    return Info::any_char();
+                }
+            }
+            Info::strings(strings)
+        }
+        HirKind::Group(ref group) => analyse(scheme, &group.hir),
+        HirKind::Repetition(ref rep) => repetition(scheme, rep),
+        HirKind::Concat(ref parts) => {
+            let mut parts = parts.iter();
+            let first = analyse(
+                scheme,
+                parts.next().expect("concat has at least two children"),
+            );
+            parts.fold(first, |acc, part| {
+                concat(scheme, acc, analyse(scheme, part))
+            })
+        }
+        HirKind::Alternation(ref parts) => {
+            let mut parts = parts.iter();
+            let first = analyse(
+                scheme,
+                parts.next().expect("alternation has at least two children"),
+            );
+            parts.fold(first, |acc, part| {
+                alternate(scheme, acc, analyse(scheme, part))
+            })
+        }
+    };
+
+    info.simplify(scheme, false);
+    info
+}
+
+fn char_symbols(scheme: &Scheme, c: char) -> Vec<u8> {
+    let mut symbols = Vec::with_capacity(1);
+    scheme.push(c, &mut symbols);
+    symbols
+}
+
+fn byte_symbols(scheme: &Scheme, b: u8) -> Vec<u8> {
+    let mut symbols = Vec::with_capacity(1);
+    scheme.push_invalid(b, &mut symbols);
+    symbols
+}
+
+/// Record the symbols for the (inclusive) range of bytes `start..end`. Unless the scheme is
+/// `bytewise`, a byte outside of ASCII is part of some char we can't see, so could be any of them.
+fn add_bytes(scheme: &Scheme, strings: &mut Set, start: u8, end: u8) -> bool {
+    if start < 0x80 && !scheme.class(start as char, end.min(0x7f) as char, strings, MAX_CLASS) {
+        return false;
+    }
+
+    if end < 0x80 {
+        return true;
+    }
+
+    if !scheme.bytewise() {
+        return scheme.class('\u{80}', char::MAX, strings, MAX_CLASS);
+    }
+
+    for b in start.max(0x80)..=end {
+        strings.insert(byte_symbols(scheme, b));
+        if strings.len() > MAX_CLASS {
+            return false;
+        }
+    }
+    true
+}
+
+fn repetition(scheme: &Scheme, rep: &hir::Repetition) -> Info {
+    use regex_syntax::hir::RepetitionKind::*;
+    use regex_syntax::hir::RepetitionRange::*;
+
+    let (min, exactly) = match rep.kind {
+        ZeroOrOne => return alternate(scheme, analyse(scheme, &rep.hir), Info::empty_string()),
+        ZeroOrMore => return Info::any_match(),
+        OneOrMore => return plus(analyse(scheme, &rep.hir)),
+        Range(Exactly(n)) => (n, true),
+        Range(AtLeast(n)) => (n, false),
+        Range(Bounded(n, m)) => (n, n == m),
+    };
+
+    if 0 == min {
+        return Info::any_match();
+    }
+
+    // x{4} is enough to learn most of what there is to know about x{n},
+    // and anything following the required part can be anything.
+    let copies = min.min(4);
+    let mut info = analyse(scheme, &rep.hir);
+    for _ in 1..copies {
+        info = concat(scheme, info, analyse(scheme, &rep.hir));
+    }
+
+    if copies == min && exactly {
+        info
+    } else {
+        concat(scheme, info, Info::any_match())
+    }
+}
+
+fn plus(mut info: Info) -> Info {
+    if let Some(exact) = info.exact.take() {
+        info.prefix = exact.clone();
+        info.suffix = exact;
+    }
+    info
+}
+
+fn concat(scheme: &Scheme, mut x: Info, mut y: Info) -> Info {
+    let mut query =
+        mem::replace(&mut x.query, TriQuery::All).and(mem::replace(&mut y.query, TriQuery::All));
+
+    let mut xy = match (&x.exact, &y.exact) {
+        (&Some(ref left), &Some(ref right)) => Info {
+            exact: Some(cross(left, right)),
+            ..Info::empty_string()
+        },
+        _ => {
+            let prefix = match x.exact {
+                Some(ref exact) => cross(exact, y.prefixes()),
+                None if x.can_empty => x.prefix.union(y.prefixes()).cloned().collect(),
+                None => x.prefix.clone(),
+            };
+
+            let suffix = match y.exact {
+                Some(ref exact) => cross(x.suffixes(), exact),
+                None if y.can_empty => y.suffix.union(x.suffixes()).cloned().collect(),
+                None => y.suffix.clone(),
+            };
+
+            // Anything spanning the join must be present, if it's long enough to have trigrams.
+            if x.exact.is_none()
+                && y.exact.is_none()
+                && x.suffix.len() <= MAX_SET
+                && y.prefix.len() <= MAX_SET
+                && min_len(&x.suffix) + min_len(&y.prefix) >= 3
+            {
+                query = and_trigrams(scheme, query, &cross(&x.suffix, &y.prefix));
+            }
+
+            Info {
+                exact: None,
+                prefix,
+                suffix,
+                ..Info::any_match()
+            }
+        }
+    };
+
+    xy.can_empty = x.can_empty && y.can_empty;
+    xy.query = query;
+    xy.simplify(scheme, false);
+    xy
+}
+
+fn alternate(scheme: &Scheme, mut x: Info, mut y: Info) -> Info {
+    let mut xy = match (x.exact.is_some(), y.exact.is_some()) {
+        (true, true) => Info {
+            exact: Some(
+                x.exact
+                    .as_ref()
+                    .unwrap()
+                    .union(y.exact.as_ref().unwrap())
+                    .cloned()
+                    .collect(),
+            ),
+            ..Info::empty_string()
+        },
+        _ => {
+            let prefix = x.prefixes().union(y.prefixes()).cloned().collect();
+            let suffix = x.suffixes().union(y.suffixes()).cloned().collect();
+            x.add_exact(scheme);
+            y.add_exact(scheme);
+            Info {
+                exact: None,
+                prefix,
+                suffix,
+                ..Info::any_match()
+            }
+        }
+    };
+
+    xy.can_empty = x.can_empty || y.can_empty;
+    xy.query = x.query.or(y.query);
+    xy.simplify(scheme, false);
+    xy
+}
+
+/// Add the trigrams from `set` to `query`, then reduce the set to strings of at most two symbols.
+fn simplify_set(scheme: &Scheme, set: &mut Set, query: TriQuery, is_suffix: bool) -> TriQuery {
+    let query = and_trigrams(scheme, query, set);
+
+    let mut n = 3;
+    while n == 3 || (set.len() > MAX_SET && n > 0) {
+        *set = set
+            .iter()
+            .map(|s| {
+                if s.len() < n {
+                    s.clone()
+                } else if is_suffix {
+                    s[s.len() - (n - 1)..].to_vec()
+                } else {
+                    s[..n - 1].to_vec()
+                }
+            })
+            .collect();
+        n -= 1;
+    }
+
+    // If "ab" is a possible prefix, knowing that "abc" is too doesn't help.
+    let redundant: Vec<Vec<u8>> = set
+        .iter()
+        .filter(|s| {
+            set.iter().any(|other| {
+                other.len() < s.len()
+                    && if is_suffix {
+                        s.ends_with(other)
+                    } else {
+                        s.starts_with(other)
+                    }
+            })
+        })
+        .cloned()
+        .collect();
+
+    for s in redundant {
+        set.remove(&s);
+    }
+
+    query
+}
+
+/// A document containing any of these strings must contain all of its trigrams.
+fn and_trigrams(scheme: &Scheme, query: TriQuery, set: &Set) -> TriQuery {
+    if min_len(set) < 3 {
+        // a short string could match anywhere, so we know nothing
+        return query;
+    }
+
+    let any = set
+        .iter()
+        .fold(TriQuery::Nothing, |acc, s| acc.or(trigrams_of(scheme, s)));
+    query.and(any)
+}
+
+fn trigrams_of(scheme: &Scheme, symbols: &[u8]) -> TriQuery {
+    if symbols.len() < 3 {
+        return TriQuery::All;
+    }
+
+    let tris: BTreeSet<u32> = symbols
+        .windows(3)
+        .map(|w| scheme.pack([w[0], w[1], w[2]]))
+        .collect();
+
+    tris.into_iter()
+        .fold(TriQuery::All, |acc, t| acc.and(TriQuery::Tri(t)))
+}
+
+fn cross(left: &Set, right: &Set) -> Set {
+    let mut ret = Set::new();
+    for l in left {
+        for r in right {
+            let mut s = l.clone();
+            s.extend(r);
+            ret.insert(s);
+        }
+    }
+    ret
+}
+
+fn min_len(set: &Set) -> usize {
+    set.iter().map(|s| s.len()).min().unwrap_or(0)
+}
+
+fn single<T: Ord>(val: T) -> BTreeSet<T> {
+    let mut set = BTreeSet::new();
+    set.insert(val);
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use regex_syntax::Parser;
+    use regex_syntax::ParserBuilder;
+
+    use super::*;
+    use tri;
+    use tri::Bytes;
+    use tri::Simplified;
+
+    fn plan(pattern: &str) -> TriQuery {
+        from_regex(&Simplified, &Parser::new().parse(pattern).unwrap())
+    }
+
+    fn tri(s: &str) -> TriQuery {
+        let symbols: Vec<u8> = s.chars().map(tri::simplify).collect();
+        TriQuery::Tri(tri::pack([symbols[0], symbols[1], symbols[2]]))
+    }
+
+    fn or(mut parts: Vec<TriQuery>) -> TriQuery {
+        parts.sort();
+        TriQuery::Or(parts)
+    }
+
+    fn and(mut parts: Vec<TriQuery>) -> TriQuery {
+        parts.sort();
+        TriQuery::And(parts)
+    }
+
+    #[test]
+    fn literal() {
+        assert_eq!(and(vec![tri("hel"), tri("ell"), tri("llo")]), plan("hello"));
+        assert_eq!(from_literal(&Simplified, "hello"), plan("hello"));
+        assert_eq!(tri("foo"), plan("foo"));
+    }
+
+    #[test]
+    fn case_is_already_folded() {
+        assert_eq!(plan("hello"), plan("(?i)hello"));
+        assert_eq!(plan("hello"), plan("[hH]ello"));
+    }
+
+    #[test]
+    fn alternation() {
+        assert_eq!(or(vec![tri("foo"), tri("bar")]), plan("foo|bar"));
+        assert_eq!(or(vec![tri("foo"), tri("bar")]), plan("(foo|bar)+"));
+    }
+
+    #[test]
+    fn too_broad() {
+        assert_eq!(TriQuery::All, plan("a.*b"));
+        assert_eq!(TriQuery::All, plan("ab"));
+        assert_eq!(TriQuery::All, plan("(foo)?"));
+        assert_eq!(TriQuery::All, plan("foo|.b"));
+    }
+
+    #[test]
+    fn gaps() {
+        assert_eq!(and(vec![tri("foo"), tri("bar")]), plan("foo.*bar"));
+        assert_eq!(and(vec![tri("foo"), tri("bar")]), plan("foo.+bar"));
+    }
+
+    #[test]
+    fn boolean() {
+        use query::parse;
+        let expr = |q: &str| from_expr(&Simplified, &parse(q).unwrap().expr.unwrap());
+
+        assert_eq!(and(vec![tri("foo"), tri("bar")]), expr("foo bar"));
+        assert_eq!(or(vec![tri("foo"), tri("bar")]), expr("foo OR bar"));
+        assert_eq!(tri("foo"), expr("foo -bar"));
+        assert_eq!(TriQuery::All, expr("foo OR -bar"));
+    }
+
+    #[test]
+    fn short() {
+        assert_eq!(tri("foo"), from_short_literal(&Simplified, "foo"));
+        assert_eq!(TriQuery::All, from_short_literal(&Simplified, "f"));
+
+        match from_short_literal(&Simplified, "->") {
+            TriQuery::Or(parts) => {
+                assert_eq!(128, parts.len());
+                assert!(parts.contains(&tri("->a")));
+                assert!(parts.contains(&tri("a->")));
+            }
+            other => panic!("{:?}", other),
+        }
+
+        match from_short_literal(&Simplified, "==") {
+            // `===` is both
+            TriQuery::Or(parts) => assert_eq!(127, parts.len()),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn bytes() {
+        let plan = |pattern: &str| from_regex(&Bytes, &Parser::new().parse(pattern).unwrap());
+        let tri = |s: &str| {
+            let symbols = Bytes.symbols(s);
+            TriQuery::Tri(Bytes.pack([symbols[0], symbols[1], symbols[2]]))
+        };
+
+        assert_eq!(from_literal(&Bytes, "привет"), plan("привет"));
+        assert_ne!(from_literal(&Bytes, "привет"), from_literal(&Bytes, "пока"));
+        assert_eq!(plan("hello"), plan("(?i)hello"));
+
+        // one char, but three bytes
+        assert_eq!(tri("日"), plan("日"));
+        assert_eq!(or(vec![tri("日"), tri("本")]), plan("[日本]"));
+
+        // any of these could be part of a char
+        let bytes = ParserBuilder::new()
+            .allow_invalid_utf8(true)
+            .build()
+            .parse("(?-u:[\\x80-\\xff])ab")
+            .unwrap();
+        assert_eq!(TriQuery::All, from_regex(&Bytes, &bytes));
+
+        // but a Latin-1 byte is indexed as itself
+        let latin1 = ParserBuilder::new()
+            .allow_invalid_utf8(true)
+            .build()
+            .parse("(?-u:caf\\xe9)")
+            .unwrap();
+        let pack = |symbols: &[u8]| TriQuery::Tri(Bytes.pack([symbols[0], symbols[1], symbols[2]]));
+        assert_eq!(
+            pack(b"caf").and(pack(b"af\xe9")),
+            from_regex(&Bytes, &latin1)
+        );
+        assert_eq!(
+            from_literal(&Simplified, "caf"),
+            from_regex(&Simplified, &latin1)
+        );
+
+        // Cyrillic case is only folded by the regex, so each letter is a pair of strings,
+        // and each end of the word has four ways to be spelt
+        match plan("(?i)при") {
+            TriQuery::And(ends) => {
+                assert_eq!(2, ends.len());
+                for end in ends {
+                    match end {
+                        TriQuery::Or(parts) => assert_eq!(4, parts.len()),
+                        other => panic!("{:?}", other),
+                    }
+                }
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let spans = |pattern: &str| {
+            spans_lines(
+                &ParserBuilder::new()
+                    .allow_invalid_utf8(true)
+                    .build()
+                    .parse(pattern)
+                    .unwrap(),
+            )
+        };
+
+        assert!(spans(r"foo\nbar"));
+        assert!(spans(r"foo(\r?\n|\n\r)"));
+        assert!(spans(r"foo\n+"));
+        assert!(spans(r"foo[\n]{2,}"));
+        assert!(spans(r"(?s)foo.*bar"));
+        assert!(spans(r"(?s-u)foo.bar"));
+        assert!(spans(r"foo[\s\S]*bar"));
+
+        assert!(!spans(r"foo.*bar"));
+        assert!(!spans(r"(?-u)foo.bar"));
+
+        // these can match a newline, but can match within a line too
+        assert!(!spans(r"foo\s+bar"));
+        assert!(!spans(r"foo[^x]bar"));
+        assert!(!spans(r"foo(bar|\n)"));
+        assert!(!spans(r"foo\n?bar"));
+        assert!(!spans(r"foo\n{0,2}bar"));
+    }
+}
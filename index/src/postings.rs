@@ -0,0 +1,419 @@
+//! Compressed posting lists, as written into index segments.
+//!
+//! A list is split into blocks of `BLOCK_LEN` document ids. It starts with a skip table,
+//! holding the first id of each block and where the rest of the block's data is,
+//! so a cursor can jump to the block which might hold an id without decoding any before it.
+//! Within a block, each id is the gap from the previous one, in `ALIGN`s, as a varint.
+//!
+//! Merged indexes have wide lists, with u64 ids: the pack in the top 32 bits, and the offset
+//! in it below. Those have u64 first ids in their skip table, but are otherwise the same.
+
+use std::cmp;
+
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use errors::*;
+
+pub const BLOCK_LEN: usize = 128;
+
+/// Records in a pack are aligned to this, so every gap is a multiple of it.
+const ALIGN: u64 = 16;
+
+/// Bytes per skip table entry: the first id, then the offset of the block's data.
+fn skip_len(wide: bool) -> usize {
+    (if wide { 8 } else { 4 }) + 4
+}
+
+/// Encode the strictly increasing, aligned `ids`, padded with zeros to a whole number of u32s.
+pub fn encode(ids: &[u32]) -> Vec<u8> {
+    encode_ids(ids, false)
+}
+
+/// Encode strictly increasing, aligned, u64 `ids`, as `encode` does.
+pub fn encode_wide(ids: &[u64]) -> Vec<u8> {
+    encode_ids(ids, true)
+}
+
+fn encode_ids<I: Copy + Into<u64>>(ids: &[I], wide: bool) -> Vec<u8> {
+    let skip_len = skip_len(wide);
+    let blocks = (ids.len() + BLOCK_LEN - 1) / BLOCK_LEN;
+    let mut skips = vec![0u8; blocks * skip_len];
+    let mut data = Vec::with_capacity(ids.len());
+
+    for (block, ids) in ids.chunks(BLOCK_LEN).enumerate() {
+        let skip = &mut skips[block * skip_len..(block + 1) * skip_len];
+        let first = ids[0].into();
+        if wide {
+            LittleEndian::write_u64(&mut skip[..8], first);
+        } else {
+            LittleEndian::write_u32(&mut skip[..4], first as u32);
+        }
+        LittleEndian::write_u32(&mut skip[skip_len - 4..], data.len() as u32);
+
+        for pair in ids.windows(2) {
+            let (prev, next) = (pair[0].into(), pair[1].into());
+            assert!(prev < next, "ids must be strictly increasing");
+            let gap = next - prev;
+            assert_eq!(0, gap % ALIGN, "ids must be aligned");
+            write_varint(&mut data, gap / ALIGN);
+        }
+    }
+
+    skips.extend(data);
+    while 0 != skips.len() % 4 {
+        skips.push(0);
+    }
+
+    skips
+}
+
+fn write_varint(into: &mut Vec<u8>, mut val: u64) {
+    while val >= 0x80 {
+        into.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    into.push(val as u8);
+}
+
+/// The value, and how many bytes it took up.
+fn read_varint(from: &[u8]) -> Result<(u64, usize)> {
+    let mut val = 0u64;
+    // ten bytes of seven bits is enough for any u64
+    for (i, byte) in from.iter().take(10).enumerate() {
+        val |= ((byte & 0x7f) as u64) << (7 * i);
+        if 0 == byte & 0x80 {
+            return Ok((val, i + 1));
+        }
+    }
+    bail!(ErrorKind::BadIndex("truncated varint".to_string()))
+}
+
+/// The document ids containing a trigram: `count` of them, encoded in `bytes`.
+#[derive(Copy, Clone, Debug)]
+pub struct PostingList<'f> {
+    count: usize,
+    bytes: &'f [u8],
+    wide: bool,
+}
+
+impl<'f> PostingList<'f> {
+    /// The `count` ids in `bytes`, as `encode` or, if `wide`, `encode_wide` wrote them.
+    /// Only the skip table is checked; the rest is as it's decoded.
+    pub fn new(count: usize, bytes: &'f [u8], wide: bool) -> Result<PostingList<'f>> {
+        let list = PostingList { count, bytes, wide };
+        if list.blocks() * skip_len(wide) > bytes.len() {
+            bail!(ErrorKind::BadIndex(format!(
+                "{} bytes is too short for a list of {}",
+                bytes.len(),
+                count
+            )));
+        }
+        Ok(list)
+    }
+
+    pub fn empty() -> PostingList<'f> {
+        PostingList {
+            count: 0,
+            bytes: &[],
+            wide: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        0 == self.count
+    }
+
+    /// How many `BLOCK_LEN` blocks the ids are split into.
+    pub fn blocks(&self) -> usize {
+        (self.count + BLOCK_LEN - 1) / BLOCK_LEN
+    }
+
+    pub fn cursor(&self) -> PostingCursor<'f> {
+        PostingCursor::Packed(PackedCursor {
+            count: self.count,
+            bytes: self.bytes,
+            skip_len: skip_len(self.wide),
+            block: 0,
+            decoded: Vec::with_capacity(cmp::min(self.count, BLOCK_LEN)),
+            next: 0,
+            done: 0 == self.count,
+        })
+    }
+
+    /// Every id, decoding all of them.
+    pub fn to_vec(&self) -> Result<Vec<u64>> {
+        let mut ids = Vec::with_capacity(self.count);
+        let mut cursor = self.cursor();
+        let mut target = 0;
+        while let Some(id) = cursor.seek(target)? {
+            ids.push(id);
+            target = match id.checked_add(1) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        Ok(ids)
+    }
+}
+
+/// Walks forwards through a list, skipping as much as it can.
+pub enum PostingCursor<'a> {
+    Ids { ids: &'a [u64], next: usize },
+    Packed(PackedCursor<'a>),
+}
+
+pub struct PackedCursor<'a> {
+    count: usize,
+    bytes: &'a [u8],
+    skip_len: usize,
+
+    /// Which block is in `decoded`.
+    block: usize,
+    decoded: Vec<u64>,
+
+    /// Index into `decoded` of the current id.
+    next: usize,
+    done: bool,
+}
+
+impl<'a> PostingCursor<'a> {
+    /// Over ids which have already been worked out.
+    pub fn ids(ids: &'a [u64]) -> PostingCursor<'a> {
+        PostingCursor::Ids { ids, next: 0 }
+    }
+
+    /// Roughly how many ids are left.
+    pub fn len(&self) -> usize {
+        match *self {
+            PostingCursor::Ids { ids, next } => ids.len() - next,
+            PostingCursor::Packed(ref packed) => packed.count,
+        }
+    }
+
+    /// Move to the first id which is at least `target`, and return it.
+    /// The cursor stays there, so seeking to the same `target` again returns it again.
+    pub fn seek(&mut self, target: u64) -> Result<Option<u64>> {
+        match *self {
+            PostingCursor::Ids { ids, ref mut next } => Ok(gallop(ids, next, target)),
+            PostingCursor::Packed(ref mut packed) => packed.seek(target),
+        }
+    }
+}
+
+/// Seek through a plain list: gallop, so seeking a short way is cheap, then search what we jumped over.
+fn gallop(ids: &[u64], next: &mut usize, target: u64) -> Option<u64> {
+    let rest = &ids[*next..];
+
+    let mut step = 1;
+    while step < rest.len() && rest[step] < target {
+        step *= 2;
+    }
+
+    let end = cmp::min(step + 1, rest.len());
+    let found = match rest[..end].binary_search(&target) {
+        Ok(idx) | Err(idx) => idx,
+    };
+
+    *next += found;
+    ids.get(*next).cloned()
+}
+
+impl<'a> PackedCursor<'a> {
+    fn blocks(&self) -> usize {
+        (self.count + BLOCK_LEN - 1) / BLOCK_LEN
+    }
+
+    /// The skip table fits in `bytes`, as `PostingList::new` checked.
+    fn first_of(&self, block: usize) -> u64 {
+        let skip = &self.bytes[block * self.skip_len..];
+        if 4 + 4 == self.skip_len {
+            LittleEndian::read_u32(skip) as u64
+        } else {
+            LittleEndian::read_u64(skip)
+        }
+    }
+
+    fn decode(&mut self, block: usize) -> Result<()> {
+        let data = &self.bytes[self.blocks() * self.skip_len..];
+        let mut at =
+            LittleEndian::read_u32(&self.bytes[(block + 1) * self.skip_len - 4..]) as usize;
+        let len = cmp::min(BLOCK_LEN, self.count - block * BLOCK_LEN);
+
+        let mut id = self.first_of(block);
+        self.decoded.clear();
+        self.decoded.push(id);
+        for _ in 1..len {
+            let (gap, used) = match data.get(at..) {
+                Some(rest) => read_varint(rest)?,
+                None => bail!(ErrorKind::BadIndex(format!(
+                    "block {} starts at {}, after the end of the list",
+                    block, at
+                ))),
+            };
+            at += used;
+            id = match gap.checked_mul(ALIGN).and_then(|gap| id.checked_add(gap)) {
+                Some(id) => id,
+                None => bail!(ErrorKind::BadIndex(format!(
+                    "gap too big in block {}",
+                    block
+                ))),
+            };
+            self.decoded.push(id);
+        }
+
+        self.block = block;
+        self.next = 0;
+        Ok(())
+    }
+
+    fn seek(&mut self, target: u64) -> Result<Option<u64>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let in_current = self.decoded.last().map_or(false, |&last| last >= target);
+
+        if !in_current {
+            // the last block starting at or before the target, after the current one
+            let from = if self.decoded.is_empty() {
+                0
+            } else {
+                self.block + 1
+            };
+
+            let mut lo = from;
+            let mut hi = self.blocks();
+            if lo >= hi {
+                self.done = true;
+                return Ok(None);
+            }
+
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if self.first_of(mid) <= target {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+
+            let block = if lo > from { lo - 1 } else { from };
+            self.decode(block)?;
+
+            if *self.decoded.last().unwrap() < target {
+                // it's in the gap before the next block, which starts after the target
+                if block + 1 == self.blocks() {
+                    self.done = true;
+                    return Ok(None);
+                }
+                self.decode(block + 1)?;
+            }
+        }
+
+        // only out of order ids, which the blocks' first ids disagree with, won't have one
+        match self.decoded[self.next..]
+            .iter()
+            .position(|&id| id >= target)
+        {
+            Some(found) => self.next += found,
+            None => bail!(ErrorKind::BadIndex(format!(
+                "ids out of order in block {}",
+                self.block
+            ))),
+        }
+
+        Ok(Some(self.decoded[self.next]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packed(bytes: &[u8], count: usize) -> PostingList {
+        PostingList::new(count, bytes, false).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        for &len in &[0, 1, 2, BLOCK_LEN - 1, BLOCK_LEN, BLOCK_LEN + 1, 1000] {
+            let ids: Vec<u32> = (0..len as u32).map(|i| 16 + i * i * 16).collect();
+            let bytes = encode(&ids);
+            assert_eq!(0, bytes.len() % 4);
+            let wide: Vec<u64> = ids.iter().map(|&id| id as u64).collect();
+            assert_eq!(wide, packed(&bytes, len).to_vec().unwrap());
+        }
+    }
+
+    #[test]
+    fn wide() {
+        // three packs' worth
+        let ids: Vec<u64> = (0..3u64)
+            .flat_map(|pack| (1..300u64).map(move |i| pack << 32 | i * 16))
+            .collect();
+        let bytes = encode_wide(&ids);
+        let list = PostingList::new(ids.len(), &bytes, true).unwrap();
+
+        assert_eq!(ids, list.to_vec().unwrap());
+
+        let mut cursor = list.cursor();
+        assert_eq!(Some(1 << 32 | 16), cursor.seek(299 * 16 + 1).unwrap());
+        assert_eq!(Some(2 << 32 | 32), cursor.seek(2 << 32 | 17).unwrap());
+        assert_eq!(None, cursor.seek(3 << 32).unwrap());
+    }
+
+    #[test]
+    fn truncated() {
+        let ids: Vec<u32> = (1..1000).map(|i| i * i * 16).collect();
+        let bytes = encode(&ids);
+
+        // too short for even the skip table
+        assert!(PostingList::new(ids.len(), &bytes[..8], false).is_err());
+
+        // the skip table, but not all of the blocks
+        let skips = 8 * ((ids.len() + BLOCK_LEN - 1) / BLOCK_LEN);
+        for &len in &[skips, skips + 10, bytes.len() / 2] {
+            let list = PostingList::new(ids.len(), &bytes[..len], false).unwrap();
+            assert!(list.to_vec().is_err());
+        }
+
+        // a block which starts past the end
+        let mut bad = bytes.clone();
+        LittleEndian::write_u32(&mut bad[4..], 0xffff_ff00);
+        assert!(packed(&bad, ids.len()).cursor().seek(0).is_err());
+    }
+
+    #[test]
+    fn small() {
+        let ids: Vec<u32> = (1..1000).map(|i| i * 16).collect();
+        assert!(encode(&ids).len() < ids.len() * 4 / 3);
+    }
+
+    #[test]
+    fn seek() {
+        let ids: Vec<u32> = (1..1000).map(|i| i * 32).collect();
+        let bytes = encode(&ids);
+
+        let wide: Vec<u64> = ids.iter().map(|&id| id as u64).collect();
+        let cursors = vec![
+            packed(&bytes, ids.len()).cursor(),
+            PostingCursor::ids(&wide),
+        ];
+
+        for mut cursor in cursors {
+            assert_eq!(Some(32), cursor.seek(0).unwrap());
+            assert_eq!(Some(32), cursor.seek(32).unwrap());
+            assert_eq!(Some(64), cursor.seek(33).unwrap());
+            assert_eq!(Some(5120), cursor.seek(5120 - 15).unwrap());
+            assert_eq!(Some(5120), cursor.seek(100).unwrap());
+            assert_eq!(Some(999 * 32), cursor.seek(999 * 32).unwrap());
+            assert_eq!(None, cursor.seek(999 * 32 + 1).unwrap());
+            assert_eq!(None, cursor.seek(0).unwrap());
+        }
+    }
+}
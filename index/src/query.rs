@@ -0,0 +1,299 @@
+//! The query language typed into the search box: some text to search for,
+//! and some operators, like `path:src/` or `-ext:html`, to narrow down where to look.
+
+use errors::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query {
+    /// What to actually search for in the documents, if anything.
+    pub expr: Option<Expr>,
+    pub filters: Vec<Filter>,
+}
+
+/// `foo bar` and `foo AND bar` both need both, `foo OR bar` needs either, `-foo` needs it absent,
+/// and `"foo bar"` is a single term, with the space. `AND` binds tighter than `OR`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Term(String),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub value: String,
+
+    /// Only keep documents which *don't* match, written as `-path:foo`.
+    pub negated: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FilterKind {
+    /// Any component of the path contains the value.
+    Path,
+    /// The file name ends with `.value`.
+    Ext,
+    /// The file is in a container from the source package named exactly `value`.
+    Package,
+}
+
+impl FilterKind {
+    fn from_name(name: &str) -> Option<FilterKind> {
+        match name {
+            "path" => Some(FilterKind::Path),
+            "ext" => Some(FilterKind::Ext),
+            "package" => Some(FilterKind::Package),
+            _ => None,
+        }
+    }
+}
+
+enum Token {
+    Term(String),
+    Not(String),
+    And,
+    Or,
+}
+
+/// Split the operators out of the query, and parse the rest into an expression.
+/// Anything which isn't a known operator is a term, so `std::vector` is just a search for `std::vector`.
+pub fn parse(input: &str) -> Result<Query> {
+    let mut filters = Vec::new();
+    let mut tokens = Vec::new();
+
+    let mut rest = input.trim_left();
+    while !rest.is_empty() {
+        let negated = rest.starts_with("-\"");
+        if negated || rest.starts_with('"') {
+            let phrase = &rest[if negated { 2 } else { 1 }..];
+            let close = match phrase.find('"') {
+                Some(close) => close,
+                None => bail!(ErrorKind::InvalidQuery("unterminated quote".to_string())),
+            };
+
+            if 0 == close {
+                bail!(ErrorKind::InvalidQuery("empty quotes".to_string()));
+            }
+
+            let term = phrase[..close].to_string();
+            tokens.push(if negated {
+                Token::Not(term)
+            } else {
+                Token::Term(term)
+            });
+            rest = phrase[close + 1..].trim_left();
+            continue;
+        }
+
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let word = &rest[..end];
+        rest = rest[end..].trim_left();
+
+        if "AND" == word {
+            tokens.push(Token::And);
+        } else if "OR" == word {
+            tokens.push(Token::Or);
+        } else if let Some(filter) = parse_filter(word)? {
+            filters.push(filter);
+        } else if is_negated(word) {
+            tokens.push(Token::Not(word[1..].to_string()));
+        } else {
+            tokens.push(Token::Term(word.to_string()));
+        }
+    }
+
+    Ok(Query {
+        expr: parse_expr(tokens)?,
+        filters,
+    })
+}
+
+/// `-foo`, but not `->` or `--`, which people search for.
+fn is_negated(word: &str) -> bool {
+    word.starts_with('-')
+        && word[1..]
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_alphanumeric() || '_' == c)
+}
+
+/// `-path:foo` etc., or `None` if this isn't a filter at all.
+fn parse_filter(word: &str) -> Result<Option<Filter>> {
+    let (negated, rest) = if word.starts_with('-') {
+        (true, &word[1..])
+    } else {
+        (false, word)
+    };
+
+    let colon = match rest.find(':') {
+        Some(colon) => colon,
+        None => return Ok(None),
+    };
+
+    let kind = match FilterKind::from_name(&rest[..colon]) {
+        Some(kind) => kind,
+        None => return Ok(None),
+    };
+
+    let value = &rest[colon + 1..];
+    if value.is_empty() {
+        bail!(ErrorKind::InvalidQuery(format!(
+            "'{}' needs a value to filter by",
+            word
+        )));
+    }
+
+    Ok(Some(Filter {
+        kind,
+        value: value.to_string(),
+        negated,
+    }))
+}
+
+fn parse_expr(tokens: Vec<Token>) -> Result<Option<Expr>> {
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let mut alternatives = Vec::new();
+    let mut terms = Vec::new();
+
+    // whether the last thing we saw was `AND` or `OR`, or the start
+    let mut wanted = true;
+
+    for token in tokens {
+        match token {
+            Token::Term(term) => terms.push(Expr::Term(term)),
+            Token::Not(term) => terms.push(Expr::Not(Box::new(Expr::Term(term)))),
+            Token::And | Token::Or if wanted => {
+                bail!(ErrorKind::InvalidQuery(
+                    "AND and OR need something either side".to_string()
+                ));
+            }
+            Token::And => {
+                wanted = true;
+                continue;
+            }
+            Token::Or => {
+                alternatives.push(all_of(terms));
+                terms = Vec::new();
+                wanted = true;
+                continue;
+            }
+        }
+        wanted = false;
+    }
+
+    if wanted {
+        bail!(ErrorKind::InvalidQuery(
+            "AND and OR need something either side".to_string()
+        ));
+    }
+
+    alternatives.push(all_of(terms));
+
+    Ok(Some(if 1 == alternatives.len() {
+        alternatives.pop().unwrap()
+    } else {
+        Expr::Or(alternatives)
+    }))
+}
+
+fn all_of(mut terms: Vec<Expr>) -> Expr {
+    if 1 == terms.len() {
+        terms.pop().unwrap()
+    } else {
+        Expr::And(terms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(kind: FilterKind, value: &str, negated: bool) -> Filter {
+        Filter {
+            kind,
+            value: value.to_string(),
+            negated,
+        }
+    }
+
+    fn term(term: &str) -> Expr {
+        Expr::Term(term.to_string())
+    }
+
+    fn not(term: &str) -> Expr {
+        Expr::Not(Box::new(Expr::Term(term.to_string())))
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            Query {
+                expr: Some(Expr::And(vec![term("foo"), term("bar")])),
+                filters: Vec::new(),
+            },
+            parse("foo   bar").unwrap()
+        );
+
+        assert_eq!(
+            Some(term("std::vector")),
+            parse("std::vector").unwrap().expr
+        );
+        assert_eq!(None, parse("  ").unwrap().expr);
+        assert_eq!(
+            Some(Expr::And(vec![term("->"), term("--"), term("-")])),
+            parse("-> -- -").unwrap().expr
+        );
+    }
+
+    #[test]
+    fn boolean() {
+        assert_eq!(
+            Some(Expr::Or(vec![
+                Expr::And(vec![term("foo"), term("bar")]),
+                Expr::And(vec![term("baz"), not("quux")]),
+            ])),
+            parse("foo AND bar OR baz -quux").unwrap().expr
+        );
+
+        assert!(parse("foo AND").is_err());
+        assert!(parse("OR foo").is_err());
+        assert!(parse("foo AND OR bar").is_err());
+    }
+
+    #[test]
+    fn phrases() {
+        assert_eq!(
+            Some(Expr::And(vec![term("foo  bar"), not("-x"), term("path:y")])),
+            parse(r#""foo  bar" -"-x" "path:y""#).unwrap().expr
+        );
+
+        assert!(parse(r#"foo "bar"#).is_err());
+        assert!(parse(r#"foo """#).is_err());
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(
+            Query {
+                expr: Some(Expr::And(vec![term("malloc"), term("free")])),
+                filters: vec![
+                    filter(FilterKind::Path, "src/", false),
+                    filter(FilterKind::Ext, "c", false),
+                    filter(FilterKind::Path, "test", true),
+                    filter(FilterKind::Package, "glibc", false),
+                ],
+            },
+            parse("path:src/ malloc ext:c -path:test free package:glibc").unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_value() {
+        assert!(parse("foo path:").is_err());
+    }
+}
@@ -8,6 +8,7 @@ use std::io;
 use std::fs;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
 
 use errors::*;
 use names;
@@ -80,6 +81,15 @@ fn fill_shard(
     loop {
         catfight::flock(&shard.file)?;
 
+        if replaced(&shard.file, &pack_path(base_path, magic, shard.nth as u64))? {
+            // `reindex --remap` moved a compacted pack over it while we waited for the lock
+            *shard = Shard {
+                file: open_or_create_pack(base_path, magic, shard.nth as u64)?,
+                nth: shard.nth,
+            };
+            continue;
+        }
+
         let mut file_end: u64 = shard
             .file
             .seek(SeekFrom::End(0))
@@ -101,11 +111,25 @@ fn fill_shard(
     }
 }
 
+/// Whether `file` is no longer what's at `path`.
+fn replaced(file: &File, path: &Path) -> io::Result<bool> {
+    let open = file.metadata()?;
+    Ok(match fs::metadata(path) {
+        Ok(current) => current.dev() != open.dev() || current.ino() != open.ino(),
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => true,
+        Err(e) => return Err(e),
+    })
+}
+
+fn pack_path(base_path: &Path, magic: u8, nth: u64) -> PathBuf {
+    base_path.join(format!("{}.{:010}.cfp", names::name_for_magic(magic), nth))
+}
+
 fn open_or_create_pack<P: AsRef<Path>>(base_path: P, magic: u8, nth: u64) -> io::Result<File> {
-    let mut new_path = base_path.as_ref().to_path_buf();
-    new_path.push(format!("{}.{:010}.cfp", names::name_for_magic(magic), nth));
+    let new_path = pack_path(base_path.as_ref(), magic, nth);
     return fs::OpenOptions::new()
         .create(true)
+        .read(true)
         .write(true)
         .open(new_path);
 
@@ -123,3 +147,44 @@ fn open_or_create_pack<P: AsRef<Path>>(base_path: P, magic: u8, nth: u64) -> io:
         Err(ref e) => Err(e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::Write;
+
+    use catfight;
+
+    use super::*;
+
+    fn src(dir: &Path, body: &[u8]) -> File {
+        let path = dir.join("src");
+        fs::File::create(&path).unwrap().write_all(body).unwrap();
+        fs::File::open(&path).unwrap()
+    }
+
+    #[test]
+    fn replaced_pack() {
+        let dir = tempdir::TempDir::new("shards").unwrap();
+        let mut store = ShardedStore::new(dir.path());
+        let first = store
+            .store(&mut src(dir.path(), b"first"), true, &[])
+            .unwrap();
+
+        // as `reindex --remap` does, with the store's pack still open
+        let magic = first % 16;
+        let path = pack_path(dir.path(), magic as u8, 0);
+        let new = dir.path().join("new");
+        fs::copy(&path, &new).unwrap();
+        fs::rename(&new, &path).unwrap();
+
+        let second = store
+            .store(&mut src(dir.path(), b"second"), true, &[])
+            .unwrap();
+        let pack = catfight::Pack::open(&path).unwrap();
+        let poses: Vec<u64> = pack.records(16).map(|record| record.unwrap().0).collect();
+        assert_eq!(vec![first - magic, second - magic], poses);
+    }
+}
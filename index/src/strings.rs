@@ -0,0 +1,142 @@
+//! Finding the text in binary documents, like `strings(1)`, so they can be indexed and grepped.
+//!
+//! Runs of bytes which could be text, in ASCII or UTF-8, are kept, as are runs of printable ASCII
+//! interleaved with zeros, as in UTF-16LE, with the zeros dropped. Anything shorter than the
+//! minimum is probably noise, and is thrown away. Each run becomes a line.
+
+use std::cmp;
+use std::str;
+
+/// What `strings(1)` uses.
+pub const DEFAULT_MIN_RUN: u32 = 4;
+
+/// How long the printable character at the start of `bytes` is, if there is one there:
+/// ASCII other than controls, or a whole UTF-8 sequence.
+fn printable(bytes: &[u8]) -> Option<usize> {
+    let len = match bytes[0] {
+        b'\t' | 0x20..=0x7e => return Some(1),
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return None,
+    };
+
+    match bytes.get(..len).map(str::from_utf8) {
+        Some(Ok(_)) => Some(len),
+        _ => None,
+    }
+}
+
+/// The runs of at least `min_run` printable bytes in `doc`, with where each starts in it,
+/// in the order they start.
+pub fn runs(doc: &[u8], min_run: usize) -> Vec<(usize, Vec<u8>)> {
+    let mut out = Vec::new();
+
+    let mut start = 0;
+    let mut pos = 0;
+    while pos < doc.len() {
+        match printable(&doc[pos..]) {
+            Some(len) => pos += len,
+            None => {
+                keep(&mut out, start, &doc[start..pos], min_run);
+                pos += 1;
+                start = pos;
+            }
+        }
+    }
+    keep(&mut out, start, &doc[start..], min_run);
+
+    // UTF-16LE, which might not start on an even byte
+    let mut run = Vec::new();
+    for offset in 0..2 {
+        let mut start = offset;
+        for (no, pair) in doc[cmp::min(offset, doc.len())..].chunks(2).enumerate() {
+            if 2 == pair.len() && 0 == pair[1] && Some(1) == printable(pair) {
+                run.push(pair[0]);
+                continue;
+            }
+
+            keep(&mut out, start, &run, min_run);
+            run.clear();
+            start = offset + (no + 1) * 2;
+        }
+
+        keep(&mut out, start, &run, min_run);
+        run.clear();
+    }
+
+    out.sort_by_key(|&(start, _)| start);
+    out
+}
+
+/// The `runs`, one per line.
+pub fn lines(runs: &[(usize, Vec<u8>)]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(runs.iter().map(|&(_, ref run)| run.len() + 1).sum());
+    for &(_, ref run) in runs {
+        out.extend_from_slice(run);
+        out.push(b'\n');
+    }
+    out
+}
+
+/// The runs of at least `min_run` printable characters in `doc`, one per line.
+pub fn printable_runs(doc: &[u8], min_run: usize) -> Vec<u8> {
+    lines(&runs(doc, min_run))
+}
+
+fn keep(out: &mut Vec<(usize, Vec<u8>)>, start: usize, run: &[u8], min_run: usize) {
+    if !run.is_empty() && run.len() >= min_run {
+        out.push((start, run.to_vec()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::printable_runs;
+
+    #[test]
+    fn runs() {
+        assert_eq!(
+            &b"hello world\n\xc3\xa9t\xc3\xa9\n"[..],
+            &printable_runs(b"\x7fELF\x01\x02hello world\0\0ab\0\xc3\xa9t\xc3\xa9\n", 4)[..]
+        );
+
+        let utf16: Vec<u8> = "int main\nx".bytes().flat_map(|b| vec![b, 0]).collect();
+        assert_eq!(&b"int main\n"[..], &printable_runs(&utf16, 4)[..]);
+
+        let mut odd = vec![1];
+        odd.extend(&utf16);
+        assert_eq!(&b"int main\n"[..], &printable_runs(&odd, 4)[..]);
+
+        assert_eq!(&b"x\n"[..], &printable_runs(b"x", 1)[..]);
+        assert!(printable_runs(b"", 4).is_empty());
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        // a lone continuation byte, a truncated sequence, and an overlong encoding split runs
+        assert_eq!(
+            &b"abcd\nefgh\nijkl\n\xc3\xa9mno\n"[..],
+            &printable_runs(b"abcd\x80efgh\xe2\x82ijkl\xc0\xaf\xc3\xa9mno", 4)[..]
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        let mut blob = vec![0u8; 10];
+        blob.extend(b"first");
+        blob.extend(&[0u8; 7]);
+        blob.extend("wide".bytes().flat_map(|b| vec![b, 0]));
+        blob.extend(&[0u8; 3]);
+        blob.extend(b"last");
+
+        assert_eq!(
+            vec![
+                (10, b"first".to_vec()),
+                (22, b"wide".to_vec()),
+                (33, b"last".to_vec()),
+            ],
+            super::runs(&blob, 4)
+        );
+    }
+}
@@ -1,6 +1,17 @@
+//! How text is turned into trigrams, for indexing and for planning queries against the index.
+
+use std::char;
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::str;
+
+use std::collections::BTreeSet;
 use std::collections::HashSet;
 
-fn simplify(wut: char) -> u8 {
+use header;
+
+pub fn simplify(wut: char) -> u8 {
     let c = match wut {
         'a'...'z' => (wut as u8 - 'a' as u8 + 'A' as u8) as char,
         _ => wut,
@@ -80,7 +91,7 @@ pub fn explain_packed(wut: u32) -> String {
     return ret;
 }
 
-fn pack(prev: [u8; 3]) -> u32 {
+pub fn pack(prev: [u8; 3]) -> u32 {
     64 * 64 * prev[0] as u32 + 64 * prev[1] as u32 + prev[2] as u32
 }
 
@@ -107,6 +118,294 @@ pub fn trigrams_full(input: &str) -> HashSet<u32> {
     found
 }
 
+/// How text is turned into trigrams: each char becomes one or more symbols, and every three
+/// symbols in a row are a trigram. An index and the queries against it have to agree on this,
+/// so which one was used is recorded in the index's header.
+pub trait Scheme: Sync + fmt::Debug {
+    /// What an index header records this as.
+    fn id(&self) -> u32;
+
+    /// How many symbols there are; every symbol is below this.
+    fn alphabet(&self) -> u32;
+
+    /// Every trigram is below this.
+    fn max_tri(&self) -> u32;
+
+    /// Add the symbols for `c`.
+    fn push(&self, c: char, into: &mut Vec<u8>);
+
+    /// Add the symbols for a byte which isn't part of any char, as in Latin-1 text.
+    /// By default, it's whatever a lossy conversion would have turned it into.
+    fn push_invalid(&self, _byte: u8, into: &mut Vec<u8>) {
+        self.push(char::REPLACEMENT_CHARACTER, into)
+    }
+
+    /// Whether a byte outside of ASCII always gets the same symbol, `push_invalid`'s,
+    /// whether or not it's part of a char, so a query can look for one on its own.
+    fn bytewise(&self) -> bool {
+        false
+    }
+
+    fn pack(&self, symbols: [u8; 3]) -> u32;
+
+    /// Something readable for a trigram, if it can be turned back into text at all.
+    fn explain(&self, tri: u32) -> String;
+
+    /// Add the symbols for each of the chars `start..=end`, returning false,
+    /// and giving up, if that makes more than `limit` different strings of them.
+    fn class(&self, start: char, end: char, into: &mut BTreeSet<Vec<u8>>, limit: usize) -> bool {
+        for c in (start as u32)..=(end as u32) {
+            if let Some(c) = char::from_u32(c) {
+                let mut symbols = Vec::new();
+                self.push(c, &mut symbols);
+                into.insert(symbols);
+                if into.len() > limit {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn symbols(&self, input: &str) -> Vec<u8> {
+        let mut symbols = Vec::with_capacity(input.len());
+        for c in input.chars() {
+            self.push(c, &mut symbols);
+        }
+        symbols
+    }
+
+    /// Every trigram in `input`.
+    fn trigrams(&self, input: &str) -> HashSet<u32> {
+        let mut found = Trigrams::new(self);
+        found.push_str(input);
+        found.finish()
+    }
+
+    /// Every trigram in `input`, which needn't be UTF-8, as `reindex` records them.
+    fn trigrams_bytes(&self, input: &[u8]) -> HashSet<u32> {
+        let mut found = Trigrams::new(self);
+        found.push_bytes(input);
+        found.finish()
+    }
+}
+
+/// Collects the trigrams of a document written to it a piece at a time, like from a decoder,
+/// giving the same as `Scheme::trigrams_bytes` would for the whole thing.
+pub struct Trigrams<'s, S: 's + Scheme + ?Sized> {
+    scheme: &'s S,
+    found: HashSet<u32>,
+
+    /// The last three symbols, and how many there have been, up to three.
+    prev: [u8; 3],
+    seen: usize,
+
+    /// The start of a char which hasn't been completely written yet.
+    partial: Vec<u8>,
+    symbols: Vec<u8>,
+}
+
+impl<'s, S: Scheme + ?Sized> Trigrams<'s, S> {
+    pub fn new(scheme: &'s S) -> Trigrams<'s, S> {
+        Trigrams {
+            scheme,
+            found: HashSet::new(),
+            prev: [0; 3],
+            seen: 0,
+            partial: Vec::with_capacity(4),
+            symbols: Vec::with_capacity(4),
+        }
+    }
+
+    pub fn push_bytes(&mut self, mut bytes: &[u8]) {
+        // a char is at most four bytes, so this takes at most three goes
+        while !self.partial.is_empty() && !bytes.is_empty() {
+            self.partial.push(bytes[0]);
+            bytes = &bytes[1..];
+
+            let partial = self.partial.clone();
+            let rest = self.push_complete(&partial);
+            self.partial.clear();
+            self.partial.extend_from_slice(rest);
+        }
+
+        let rest = self.push_complete(bytes);
+        self.partial.extend_from_slice(rest);
+    }
+
+    /// Push everything but a char cut off at the end, which is returned.
+    fn push_complete<'b>(&mut self, mut bytes: &'b [u8]) -> &'b [u8] {
+        loop {
+            match str::from_utf8(bytes) {
+                Ok(valid) => {
+                    self.push_str(valid);
+                    return &[];
+                }
+                Err(e) => {
+                    let (valid, rest) = bytes.split_at(e.valid_up_to());
+                    self.push_str(str::from_utf8(valid).expect("checked above"));
+
+                    let invalid = match e.error_len() {
+                        Some(len) => len,
+                        None => return rest,
+                    };
+
+                    for &byte in &rest[..invalid] {
+                        self.symbols.clear();
+                        self.scheme.push_invalid(byte, &mut self.symbols);
+                        self.push_symbols();
+                    }
+                    bytes = &rest[invalid..];
+                }
+            }
+        }
+    }
+
+    pub fn push_str(&mut self, input: &str) {
+        for c in input.chars() {
+            self.symbols.clear();
+            self.scheme.push(c, &mut self.symbols);
+            self.push_symbols();
+        }
+    }
+
+    fn push_symbols(&mut self) {
+        for &symbol in &self.symbols {
+            self.prev = [self.prev[1], self.prev[2], symbol];
+            self.seen = cmp::min(self.seen + 1, 3);
+            if 3 == self.seen {
+                self.found.insert(self.scheme.pack(self.prev));
+            }
+        }
+    }
+
+    /// Every trigram, counting a char which was cut off at the end as invalid bytes.
+    pub fn finish(mut self) -> HashSet<u32> {
+        for byte in self.partial.clone() {
+            self.symbols.clear();
+            self.scheme.push_invalid(byte, &mut self.symbols);
+            self.push_symbols();
+        }
+        self.found
+    }
+}
+
+impl<'s, S: Scheme + ?Sized> io::Write for Trigrams<'s, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.push_bytes(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The scheme an index header's `scheme` names, if we know it.
+pub fn scheme(id: u32) -> Option<&'static Scheme> {
+    match id {
+        header::SCHEME_SIMPLIFIED => Some(&Simplified),
+        header::SCHEME_BYTES => Some(&Bytes),
+        _ => None,
+    }
+}
+
+/// `simplify`: every char is one of 64 symbols, so trigrams are small and dense,
+/// but all non-ASCII text looks the same.
+#[derive(Debug)]
+pub struct Simplified;
+
+/// Non-ASCII chars are mostly 63, but whitespace and control chars get their own symbols.
+const NON_ASCII: [u8; 3] = [0, 2, 63];
+
+impl Scheme for Simplified {
+    fn id(&self) -> u32 {
+        header::SCHEME_SIMPLIFIED
+    }
+
+    fn alphabet(&self) -> u32 {
+        64
+    }
+
+    fn max_tri(&self) -> u32 {
+        64 * 64 * 64
+    }
+
+    fn push(&self, c: char, into: &mut Vec<u8>) {
+        into.push(simplify(c));
+    }
+
+    fn pack(&self, symbols: [u8; 3]) -> u32 {
+        pack(symbols)
+    }
+
+    fn explain(&self, tri: u32) -> String {
+        explain_packed(tri)
+    }
+
+    /// Walking every non-ASCII char would be slow, and they only have a few symbols anyway.
+    fn class(&self, start: char, end: char, into: &mut BTreeSet<Vec<u8>>, limit: usize) -> bool {
+        for c in (start as u32)..(cmp::min(end as u32, 0x7f) + 1) {
+            into.insert(vec![simplify(c as u8 as char)]);
+        }
+
+        if end as u32 >= 0x80 {
+            into.extend(NON_ASCII.iter().map(|&symbol| vec![symbol]));
+        }
+
+        into.len() <= limit
+    }
+}
+
+/// The UTF-8 bytes, with ASCII case folded, so any script gets useful trigrams.
+/// There are 2^24 possible byte trigrams, so they're hashed down to `BYTES_TRI_BITS` bits,
+/// to keep the index's directory a sensible size.
+#[derive(Debug)]
+pub struct Bytes;
+
+const BYTES_TRI_BITS: u32 = 21;
+
+impl Scheme for Bytes {
+    fn id(&self) -> u32 {
+        header::SCHEME_BYTES
+    }
+
+    fn alphabet(&self) -> u32 {
+        256
+    }
+
+    fn max_tri(&self) -> u32 {
+        1 << BYTES_TRI_BITS
+    }
+
+    fn push(&self, c: char, into: &mut Vec<u8>) {
+        let mut buf = [0u8; 4];
+        for &b in c.encode_utf8(&mut buf).as_bytes() {
+            into.push(b.to_ascii_lowercase());
+        }
+    }
+
+    /// Just the byte, so Latin-1 text is indexed exactly as it'll be grepped.
+    fn push_invalid(&self, byte: u8, into: &mut Vec<u8>) {
+        into.push(byte);
+    }
+
+    fn bytewise(&self) -> bool {
+        true
+    }
+
+    fn pack(&self, symbols: [u8; 3]) -> u32 {
+        let tri = (symbols[0] as u32) << 16 | (symbols[1] as u32) << 8 | symbols[2] as u32;
+
+        // Fibonacci hashing: the top bits of the product are well mixed
+        tri.wrapping_mul(0x9E37_79B9) >> (32 - BYTES_TRI_BITS)
+    }
+
+    fn explain(&self, tri: u32) -> String {
+        format!("#{:06x}", tri)
+    }
+}
+
 #[cfg(never)]
 pub type CharResult = result::Result<char, io::CharsError>;
 
@@ -131,3 +430,90 @@ pub fn trigrams_for<T: Iterator<Item = CharResult>>(input: T) -> Result<BitSet>
     }
     return Ok(ret);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simplified() {
+        assert_eq!(trigrams_full("Hello"), Simplified.trigrams("hello"));
+
+        // every non-ASCII letter is the same symbol
+        assert_eq!(
+            Simplified.trigrams("привет"),
+            Simplified.trigrams("日本語の")
+        );
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(Bytes.trigrams("Hello"), Bytes.trigrams("hello"));
+        assert_ne!(Bytes.trigrams("привет"), Bytes.trigrams("пока"));
+
+        // one char is three bytes, so one trigram
+        assert_eq!(1, Bytes.trigrams("日").len());
+        assert!(Bytes
+            .trigrams("日本語")
+            .iter()
+            .all(|&tri| tri < Bytes.max_tri()));
+
+        assert_eq!(vec![0xd0, 0xbf, b'a'], Bytes.symbols("пA"));
+    }
+
+    #[test]
+    fn invalid() {
+        // Latin-1 "café au lait": the é is one byte, and not UTF-8
+        let latin1 = b"caf\xe9 au lait";
+        let lossy = Bytes.trigrams(&String::from_utf8_lossy(latin1));
+        let exact = Bytes.trigrams_bytes(latin1);
+        assert_ne!(lossy, exact);
+        assert!(exact.contains(&Bytes.pack([b'f', 0xe9, b' '])));
+
+        // anything valid is the same either way
+        assert_eq!(
+            Bytes.trigrams("привет"),
+            Bytes.trigrams_bytes("привет".as_bytes())
+        );
+
+        // what lossy conversion would have done, but for each byte
+        assert_eq!(
+            Simplified.trigrams("caf\u{fffd}\u{fffd}"),
+            Simplified.trigrams_bytes(b"caf\xe2\x82")
+        );
+    }
+
+    #[test]
+    fn streaming() {
+        use std::io::Write;
+
+        // invalid, then the euro sign; Latin-1; Cyrillic; CJK; an emoji; then a cut off char
+        let doc: &[u8] = b"\xff\xe2\x82\xac1 caf\xe9 \xd0\xbf\xd1\x80\xd0\xb8 \
+                           \xe6\x97\xa5\xf0\x9f\x98\x80\xe2\x82";
+        for scheme in &[&Simplified as &Scheme, &Bytes] {
+            let whole = scheme.trigrams_bytes(doc);
+            for chunk in 1..doc.len() + 1 {
+                let mut found = Trigrams::new(*scheme);
+                for piece in doc.chunks(chunk) {
+                    found.write_all(piece).unwrap();
+                }
+                assert_eq!(whole, found.finish(), "{:?} in {}s", scheme, chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn class() {
+        let mut strings = BTreeSet::new();
+        assert!(Simplified.class('a', 'c', &mut strings, 16));
+        assert!(Simplified.class('A', 'C', &mut strings, 16));
+        assert_eq!(3, strings.len());
+        assert!(Simplified.class('\u{80}', '\u{10ffff}', &mut strings, 16));
+        assert_eq!(6, strings.len());
+
+        let mut strings = BTreeSet::new();
+        assert!(Bytes.class('а', 'я', &mut strings, 32));
+        assert_eq!(32, strings.len());
+        assert!(!Bytes.class('\u{80}', '\u{10ffff}', &mut strings, 32));
+    }
+}
@@ -1,31 +1,335 @@
-extern crate byteorder;
+//! Statistics for index segments, and a check that every posting in one is a record in its pack.
+
 extern crate index;
+#[macro_use]
+extern crate serde_json;
 
+use std::collections::HashSet;
 use std::env;
 use std::fs;
-use std::io;
+use std::path;
+use std::process;
+
+use index::find::Segment;
+use index::header;
+
+/// How many of the heaviest trigrams to list, unless told otherwise.
+const DEFAULT_TOP: usize = 20;
+
+/// How many bad postings to describe, so a broken index doesn't print every one.
+const MAX_BAD: usize = 10;
+
+struct Options {
+    top: usize,
+
+    /// List every trigram's count, not just the heaviest.
+    all: bool,
+    json: bool,
+}
+
+struct Stats {
+    bytes: u64,
+
+    /// In the parts of the packs the segment covers.
+    records: usize,
+
+    /// Records which are in any list; anything shorter than a trigram isn't.
+    documents: usize,
+    trigrams: usize,
+    postings: usize,
+
+    /// How many `postings::BLOCK_LEN` blocks the lists are split into.
+    blocks: usize,
+
+    /// The count for every trigram with any postings, heaviest first.
+    counts: Vec<(usize, u32)>,
+    checksum_ok: bool,
+
+    /// Lists which can't be decoded, so weren't checked.
+    bad_lists: usize,
+
+    /// How many postings aren't the start of a record, and what the first few of those, and of
+    /// the bad lists, are.
+    bad_postings: usize,
+    bad: Vec<String>,
+}
+
+impl Stats {
+    fn healthy(&self) -> bool {
+        self.checksum_ok && 0 == self.bad_lists && 0 == self.bad_postings
+    }
+}
+
+fn format_name(segment: &Segment) -> &'static str {
+    match segment.header.format {
+        index::find::FORMAT_PACKED if segment.packs.len() > 1 => "wide",
+        index::find::FORMAT_PACKED => "packed",
+        _ => "unknown",
+    }
+}
+
+fn scheme_name(scheme: u32) -> &'static str {
+    match scheme {
+        header::SCHEME_SIMPLIFIED => "simplified",
+        header::SCHEME_BYTES => "bytes",
+        _ => "unknown",
+    }
+}
+
+/// Read every list in the segment, checking each id against the records in its pack.
+/// A list which can't be read is counted, not fatal, so the rest of a damaged segment is still checked.
+fn stats(path: &path::Path, segment: &Segment) -> index::Result<Stats> {
+    let mut records = Vec::with_capacity(segment.packs.len());
+    for (pack, range) in segment.packs.iter().zip(&segment.header.packs) {
+        records.push(index::find::records(pack, range.start, range.pack_len)?);
+    }
+
+    let mut stats = Stats {
+        bytes: fs::metadata(path)?.len(),
+        records: records.iter().map(|records| records.len()).sum(),
+        documents: 0,
+        trigrams: 0,
+        postings: 0,
+        blocks: 0,
+        counts: Vec::new(),
+        checksum_ok: segment.verify().is_ok(),
+        bad_lists: 0,
+        bad_postings: 0,
+        bad: Vec::new(),
+    };
+
+    let mut documents = HashSet::new();
+
+    for tri in 0..segment.scheme.max_tri() {
+        let decoded = segment.postings(tri).and_then(|list| {
+            let ids = list.to_vec()?;
+            Ok((list, ids))
+        });
+
+        let (list, ids) = match decoded {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                stats.bad_lists += 1;
+                if stats.bad.len() < MAX_BAD {
+                    // the cause, as the trigram is already said
+                    let cause = e.iter().last().map_or(String::new(), |e| e.to_string());
+                    stats.bad.push(format!(
+                        "trigram {} ({}): {}",
+                        tri,
+                        segment.scheme.explain(tri),
+                        cause
+                    ));
+                }
+                continue;
+            }
+        };
+
+        if list.is_empty() {
+            continue;
+        }
+
+        stats.trigrams += 1;
+        stats.postings += list.len();
+        stats.counts.push((list.len(), tri));
+        stats.blocks += list.blocks();
+
+        for id in ids {
+            let (pack, local) = index::find::split_id(id);
+            let found = records
+                .get(pack)
+                .map_or(false, |records| records.binary_search(&local).is_ok());
 
-use io::Read;
-use io::Seek;
-use io::SeekFrom;
+            if found {
+                documents.insert(id);
+            } else {
+                stats.bad_postings += 1;
+                if stats.bad.len() < MAX_BAD {
+                    stats.bad.push(format!(
+                        "trigram {} ({}): pack {}, offset {}",
+                        tri,
+                        segment.scheme.explain(tri),
+                        pack,
+                        local
+                    ));
+                }
+            }
+        }
+    }
+
+    stats.documents = documents.len();
+    stats.counts.sort_by(|left, right| right.cmp(left));
+
+    Ok(stats)
+}
+
+fn print_text(path: &path::Path, segment: &Segment, stats: &Stats, options: &Options) {
+    let header = &segment.header;
+    println!(
+        "{:?}: {} bytes, {} lists, {} scheme",
+        path,
+        stats.bytes,
+        format_name(segment),
+        scheme_name(header.scheme)
+    );
 
-use byteorder::{LittleEndian, ReadBytesExt};
+    for range in &header.packs {
+        println!("  {}: {} to {}", range.name, range.start, range.pack_len);
+    }
+
+    println!(
+        "  {} records, {} with trigrams",
+        stats.records, stats.documents
+    );
+    println!(
+        "  {} trigrams, {} postings, {} blocks",
+        stats.trigrams, stats.postings, stats.blocks
+    );
+    println!(
+        "  checksum {}, {} unreadable lists, {} postings not at a record",
+        if stats.checksum_ok { "ok" } else { "MISMATCH" },
+        stats.bad_lists,
+        stats.bad_postings
+    );
+
+    for bad in &stats.bad {
+        println!("    {}", bad);
+    }
+
+    let explain = |&(count, tri): &(usize, u32)| {
+        println!("{:10} {:8} {}", count, tri, segment.scheme.explain(tri));
+    };
+
+    if options.all {
+        let mut by_tri = stats.counts.clone();
+        by_tri.sort_by_key(|&(_, tri)| tri);
+        by_tri.iter().for_each(explain);
+    } else {
+        stats.counts.iter().take(options.top).for_each(explain);
+    }
+}
+
+fn to_json(
+    path: &path::Path,
+    segment: &Segment,
+    stats: &Stats,
+    options: &Options,
+) -> serde_json::Value {
+    let header = &segment.header;
+    let trigram = |&(count, tri): &(usize, u32)| {
+        json!({
+            "tri": tri,
+            "explain": segment.scheme.explain(tri),
+            "count": count,
+        })
+    };
+
+    let mut value = json!({
+        "path": path.to_string_lossy(),
+        "healthy": stats.healthy(),
+        "format": format_name(segment),
+        "scheme": scheme_name(header.scheme),
+        "strings": header.strings,
+        "packs": header.packs.iter().map(|range| json!({
+            "name": range.name,
+            "start": range.start,
+            "pack_len": range.pack_len,
+        })).collect::<Vec<_>>(),
+        "bytes": stats.bytes,
+        "records": stats.records,
+        "documents": stats.documents,
+        "trigrams": stats.trigrams,
+        "postings": stats.postings,
+        "blocks": stats.blocks,
+        "checksum_ok": stats.checksum_ok,
+        "bad_lists": stats.bad_lists,
+        "bad_postings": stats.bad_postings,
+        "bad": stats.bad,
+        "top": stats.counts.iter().take(options.top).map(&trigram).collect::<Vec<_>>(),
+    });
+
+    if options.all {
+        let mut by_tri = stats.counts.clone();
+        by_tri.sort_by_key(|&(_, tri)| tri);
+        value["counts"] = by_tri.iter().map(&trigram).collect();
+    }
 
-fn read<R>(mut fp: R) -> io::Result<()>
-where
-    R: Seek + Read,
-{
-    let tri = fp.read_u32::<LittleEndian>()?;
-    let len = fp.read_u32::<LittleEndian>()? as usize;
-    fp.seek(SeekFrom::Current(len as i64 * 4))?;
-    println!("{:4} {} {}", len, tri, index::explain_packed(tri));
-    Ok(())
+    value
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} [--json] [--top N] [--all] IDX..", program);
+    process::exit(2);
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let mut fp = io::BufReader::new(fs::File::open(&args[1]).unwrap());
-    loop {
-        read(&mut fp).unwrap();
+
+    let mut options = Options {
+        top: DEFAULT_TOP,
+        all: false,
+        json: false,
+    };
+    let mut paths = Vec::new();
+
+    let mut it = args[1..].iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--json" => options.json = true,
+            "--all" => options.all = true,
+            "--top" => {
+                options.top = match it.next().map(|top| top.parse::<usize>()) {
+                    Some(Ok(top)) => top,
+                    _ => usage(&args[0]),
+                }
+            }
+            _ => paths.push(path::Path::new(arg)),
+        }
+    }
+
+    if paths.is_empty() {
+        usage(&args[0]);
+    }
+
+    let mut healthy = true;
+    let mut values = Vec::new();
+
+    for path in paths {
+        let found = Segment::open(path).and_then(|segment| {
+            let stats = stats(path, &segment)?;
+            Ok((segment, stats))
+        });
+
+        let (segment, stats) = match found {
+            Ok(found) => found,
+            Err(e) => {
+                healthy = false;
+                if options.json {
+                    values.push(json!({
+                        "path": path.to_string_lossy(),
+                        "healthy": false,
+                        "error": e.to_string(),
+                    }));
+                } else {
+                    println!("{:?}: {}", path, e);
+                }
+                continue;
+            }
+        };
+
+        healthy &= stats.healthy();
+
+        if options.json {
+            values.push(to_json(path, &segment, &stats, &options));
+        } else {
+            print_text(path, &segment, &stats, &options);
+        }
+    }
+
+    if options.json {
+        println!("{}", serde_json::Value::Array(values));
+    }
+
+    if !healthy {
+        process::exit(1);
     }
 }
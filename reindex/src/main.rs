@@ -2,15 +2,23 @@ extern crate byteorder;
 extern crate catfight;
 extern crate index;
 extern crate lz4;
-extern crate memmap;
+extern crate num_cpus;
 extern crate tempfile;
 
 use std::env;
 use std::fs;
 use std::io;
+use std::path;
+use std::process;
+use std::thread;
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
 
+use std::io::BufRead;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
@@ -18,209 +26,840 @@ use std::io::Write;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
+mod sort;
+
 type Pos = u32;
 type Tri = u32;
-type Count = u32;
-
-struct TempFileChunk {
-    num_tris: Count,
-    pos: Pos,
-}
-
-/// Consume a pack file, and generate a temporary file containing the trigrams for every entry.
-/// `temp` file contains no metadata, literally just a concatenation of the tris for the first pos,
-/// then for the second, ...
-/// The returned `temp_index` of chunks stores their length, and which chunk-relative 'pos' they refer to
-/// The `trigram_count` for every trigram is also recorded.
-fn convert_pack_to_just_trigrams<R: Read + Seek>(
-    mut pack: R,
-) -> (fs::File, Vec<TempFileChunk>, HashMap<Tri, Count>) {
-    let mut pos = 16;
-    pack.seek(SeekFrom::Start(pos)).unwrap();
-
-    let mut temp = io::BufWriter::new(tempfile::tempfile().unwrap());
-    let mut temp_index = Vec::with_capacity(200_000);
-    let mut trigram_count: HashMap<Tri, u32> = HashMap::with_capacity(64 * 64 * 64);
-
-    loop {
-        if let Some(mut entry) = catfight::read_record(&mut pack).unwrap() {
-            // len is the compressed length, but better than zero
-            let mut buf = Vec::with_capacity(entry.len as usize);
-            lz4::Decoder::new(&mut entry.reader)
-                .unwrap()
-                .read_to_end(&mut buf)
-                .unwrap();
 
-            let mut tris: Vec<u32> = index::trigrams_full(&String::from_utf8_lossy(&buf))
-                .into_iter()
-                .collect();
-            temp_index.push(TempFileChunk {
-                pos: pos as u32,
-                num_tris: tris.len() as u32,
-            });
+/// How much memory to use for sorting, unless told otherwise.
+const DEFAULT_MEMORY: usize = 400 * 1024 * 1024;
+
+struct Options {
+    /// Roughly how many bytes of trigrams to hold in memory at once.
+    memory: usize,
 
-            tris.sort();
+    /// Where sorted runs are spilled to.
+    temp_dir: path::PathBuf,
 
-            for t in tris {
-                *trigram_count.entry(t).or_insert(0) += 1;
+    /// How many threads to decompress and find trigrams on.
+    threads: usize,
 
-                // TODO: this actually should be in platform endian
-                temp.write_u32::<LittleEndian>(t).unwrap();
+    /// Only index runs of this many printable characters, as in `index::strings`, or whole
+    /// documents if it's zero. By default, binary packs get runs, and text packs whole documents.
+    strings: Option<u32>,
+
+    /// How text becomes trigrams, as in `index::tri::scheme`. By default, whatever the
+    /// index already uses, or `tri::Simplified` for a new one.
+    scheme: Option<u32>,
+}
+
+/// How many records can be queued up for, or by, the workers, per worker.
+const QUEUE_PER_THREAD: usize = 16;
+
+/// Consume a pack file from `start`, feeding the trigrams of every entry, and where it was,
+/// into the `sorter`. Returns how many entries there were, and how far through the pack we got,
+/// which is no further than the first record that hasn't been committed yet.
+///
+/// Records are read in order on one thread, then decompressed and split into trigrams on
+/// `threads` others. They finish in any order, but the sorter puts the poses back in order.
+/// If `strings` isn't zero, only the runs of printable characters in each entry are used.
+/// Text is streamed from the decoder, and its trigrams are of the bytes, as they'll be grepped.
+fn read_pack_trigrams(
+    pack: catfight::Pack,
+    start: u64,
+    strings: u32,
+    scheme: &'static index::tri::Scheme,
+    threads: usize,
+    sorter: &mut sort::Sorter,
+) -> (usize, u64) {
+    let (record_tx, record_rx) = mpsc::sync_channel::<(Pos, Vec<u8>)>(threads * QUEUE_PER_THREAD);
+    let record_rx = Arc::new(Mutex::new(record_rx));
+    let (tris_tx, tris_rx) = mpsc::sync_channel::<(Pos, HashSet<Tri>)>(threads * QUEUE_PER_THREAD);
+
+    let reader = thread::spawn(move || {
+        let mut pos = start;
+        let mut entries = 0;
+
+        for record in pack.records(start) {
+            let (_, mut entry) = record.unwrap();
+
+            // stop here, so the next delta starts here; what's after may be as unfinished
+            if !entry.committed && !entry.tombstone {
+                eprintln!(
+                    "stopping at an uncommitted record at {}; if nothing is writing, repair the pack",
+                    pos
+                );
+                break;
+            }
+
+            let mut compressed = Vec::with_capacity(entry.len as usize);
+            entry.reader.read_to_end(&mut compressed).unwrap();
+
+            // deleted records keep their place, but aren't documents
+            if !entry.tombstone {
+                record_tx.send((pos as Pos, compressed)).unwrap();
             }
 
+            entries += 1;
             pos += entry.len();
+        }
 
-            entry.complete().unwrap();
-        } else {
-            break;
+        (entries, pos)
+    });
+
+    let workers: Vec<thread::JoinHandle<()>> = (0..threads)
+        .map(|_| {
+            let record_rx = record_rx.clone();
+            let tris_tx = tris_tx.clone();
+            thread::spawn(move || loop {
+                let received = record_rx.lock().unwrap().recv();
+                let (pos, compressed) = match received {
+                    Ok(record) => record,
+                    // the reader has finished
+                    Err(_) => return,
+                };
+
+                let mut decoder = lz4::Decoder::new(&compressed[..]).unwrap();
+
+                let tris = if 0 == strings {
+                    let mut found = index::tri::Trigrams::new(scheme);
+                    io::copy(&mut decoder, &mut found).unwrap();
+                    found.finish()
+                } else {
+                    // finding the runs needs the whole document;
+                    // the compressed length, but better than zero
+                    let mut buf = Vec::with_capacity(compressed.len());
+                    decoder.read_to_end(&mut buf).unwrap();
+                    scheme.trigrams_bytes(&index::strings::printable_runs(&buf, strings as usize))
+                };
+
+                tris_tx.send((pos, tris)).unwrap();
+            })
+        })
+        .collect();
+
+    // so the loop ends when the last worker does
+    drop(tris_tx);
+
+    for (pos, tris) in tris_rx {
+        for tri in tris {
+            sorter.push(tri, pos).unwrap();
         }
     }
 
-    let mut temp = temp.into_inner().unwrap();
+    for worker in workers {
+        worker.join().unwrap();
+    }
 
-    temp.flush().unwrap();
+    reader.join().unwrap()
+}
 
-    (temp, temp_index, trigram_count)
+/// Writes an index file: space for the header, each trigram's list, in ascending order,
+/// then the directory of where they all are, then goes back and fills in the header.
+///
+/// list: [num ids, u32] [ids, encoded as in `index::postings`, and zero padded to a u32]
+/// directory: [where each trigram's list starts, after the header, u64].. [where the last ends]
+struct IndexWriter {
+    out: index::header::ChecksumWriter<io::BufWriter<fs::File>>,
+    header: index::header::Header,
+
+    /// How many trigrams the `header`'s scheme has, so how long the directory is.
+    max_tri: Tri,
+    directory: Vec<u64>,
+    written: u64,
 }
 
-/// Take the lowest trigrams out of the iterator, and prepare space to gather Poses for them.
-/// Stops at an arbitrary memory limit.
-fn take_some<I>(trigram_count: &mut I) -> HashMap<Tri, Vec<Pos>>
-where
-    I: Iterator<Item = (Tri, Count)>,
-{
-    let mut block = 0usize;
-    let mut tris: HashMap<Tri, Vec<Pos>> = HashMap::with_capacity(128);
-    loop {
-        let (tri, count) = match trigram_count.next() {
-            Some(t) => (t.0, t.1),
-            None => break,
+impl IndexWriter {
+    /// Start writing the lists for `header`, which only lacks its checksum.
+    fn create(path: &path::Path, header: index::header::Header) -> IndexWriter {
+        let max_tri = index::tri::scheme(header.scheme).unwrap().max_tri();
+        let mut out = io::BufWriter::new(fs::File::create(path).unwrap());
+        out.write_all(&vec![0u8; header.encoded_len()]).unwrap();
+        IndexWriter {
+            out: index::header::ChecksumWriter::new(out),
+            header,
+            max_tri,
+            directory: Vec::with_capacity(max_tri as usize + 1),
+            written: 0,
+        }
+    }
+
+    /// Write the list for `tri`, as ids from a segment; they're wide if there's more than one pack.
+    fn push(&mut self, tri: Tri, ids: &[u64]) {
+        let bytes = if self.header.packs.len() > 1 {
+            index::postings::encode_wide(ids)
+        } else {
+            let ids: Vec<Pos> = ids.iter().map(|&id| id as Pos).collect();
+            index::postings::encode(&ids)
         };
+        self.write_list(tri, ids.len(), &bytes);
+    }
+
+    /// Write the list for `tri`, as the poses in a pack.
+    fn push_poses(&mut self, tri: Tri, poses: &[Pos]) {
+        assert_eq!(1, self.header.packs.len());
+        self.write_list(tri, poses.len(), &index::postings::encode(poses));
+    }
+
+    fn write_list(&mut self, tri: Tri, count: usize, bytes: &[u8]) {
+        assert!(
+            tri as usize >= self.directory.len(),
+            "trigrams must be in order"
+        );
+
+        // every trigram since the last list has an empty one, ending where this starts
+        while self.directory.len() <= tri as usize {
+            self.directory.push(self.written);
+        }
+
+        if 0 == count {
+            return;
+        }
+
+        assert!(count <= std::u32::MAX as usize);
+        self.out.write_u32::<LittleEndian>(count as u32).unwrap();
+        self.out.write_all(bytes).unwrap();
+        self.written += 4 + bytes.len() as u64;
+    }
 
-        tris.insert(tri, Vec::with_capacity(count as usize));
+    /// Write the directory, then go back and fill in the header, and sync it all, ready to be renamed.
+    fn finish(mut self) {
+        while self.directory.len() <= self.max_tri as usize {
+            self.directory.push(self.written);
+        }
 
-        // ~ 400MB ram usage?
-        if block > 100_000_000 {
-            break;
+        for &offset in &self.directory {
+            self.out.write_u64::<LittleEndian>(offset).unwrap();
         }
-        block += count as usize;
+
+        self.header.checksum = self.out.checksum.value();
+
+        let mut out = self.out.into_inner().into_inner().unwrap();
+        out.seek(SeekFrom::Start(0)).unwrap();
+        out.write_all(&self.header.to_bytes().unwrap()).unwrap();
+        out.sync_all().unwrap();
     }
+}
 
-    tris.shrink_to_fit();
-    tris
+/// Where the delta segment for `idx`, covering the pack from `start`, goes.
+fn delta_path(idx: &path::Path, start: u64) -> path::PathBuf {
+    let stem = idx.file_stem().and_then(|stem| stem.to_str()).unwrap();
+    idx.with_file_name(format!("{}.delta-{:012}.idx", stem, start))
 }
 
-fn fill_tris(temp_index: &[TempFileChunk], temp_data: &[Tri], tris: &mut HashMap<Tri, Vec<Pos>>) {
-    // inclusive
-    let min = *tris.keys().min().unwrap();
-    let max = *tris.keys().max().unwrap();
+/// The directory a file is in, even if it was given without one.
+fn dir_of(file: &path::Path) -> &path::Path {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => path::Path::new("."),
+    }
+}
 
-    let mut run = 0usize;
+/// The delta segments which have been written for `idx`, in order.
+fn deltas(idx: &path::Path) -> Vec<path::PathBuf> {
+    let prefix = format!(
+        "{}.delta-",
+        idx.file_stem().and_then(|stem| stem.to_str()).unwrap()
+    );
+
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir_of(idx)).unwrap() {
+        let path = entry.unwrap().path();
+        let is_delta = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| {
+                name.starts_with(&prefix) && name.ends_with(".idx")
+            });
+        if is_delta {
+            found.push(path);
+        }
+    }
 
-    // For every document...
-    for part in temp_index {
-        // Work out what part of its trigrams we even need to look at...
-        let subslice = &temp_data[run..(run + (part.num_tris as usize))];
-        let mut start = match subslice.binary_search(&min) {
-            Ok(idx) | Err(idx) => idx,
-        };
+    found.sort();
+    found
+}
+
+fn read_header(path: &path::Path) -> index::header::Header {
+    index::header::Header::read(io::BufReader::new(fs::File::open(path).unwrap())).unwrap()
+}
+
+/// A base index and its deltas, in order.
+fn open_segments<'f>(idx: &path::Path) -> Vec<index::find::Segment<'f>> {
+    let mut segments = Vec::new();
+    for path in Some(idx.to_path_buf()).into_iter().chain(deltas(idx)) {
+        let segment = index::find::Segment::open(&path).unwrap();
 
-        if start > 1 {
-            start -= 2;
+        // we're about to rewrite it, so make sure it's worth keeping
+        segment.verify().unwrap();
+        segments.push(segment);
+    }
+    index::find::chain_segments(segments).unwrap()
+}
+
+/// Index the part of the pack which `idx` and its deltas don't cover yet, if any;
+/// the whole thing, into `idx`, if it doesn't exist.
+fn reindex(pack_path: &path::Path, idx: &path::Path, options: &Options) {
+    let pack_name = pack_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap()
+        .to_string();
+    let (_, addendum) = index::names::addendum_from_path(&pack_name).unwrap();
+
+    let mut start = index::header::PACK_START;
+    let mut strings = options.strings;
+    let mut scheme = options.scheme;
+    if idx.exists() {
+        for segment in Some(idx.to_path_buf()).into_iter().chain(deltas(idx)) {
+            let header = read_header(&segment);
+            assert!(
+                1 == header.packs.len() && pack_name == header.packs[0].name,
+                "{:?} is an index of a different pack",
+                segment
+            );
+            start = std::cmp::max(start, header.packs[0].pack_len);
+
+            // a delta has to be searched the same way as the rest
+            assert!(
+                strings.map_or(true, |strings| strings == header.strings),
+                "{:?} was indexed with --strings {}; delete it to change that",
+                segment,
+                header.strings
+            );
+            strings = Some(header.strings);
+
+            // and its trigrams have to mean the same thing
+            assert!(
+                scheme.map_or(true, |scheme| scheme == header.scheme),
+                "{:?} was indexed with trigram scheme {}; delete it to change that",
+                segment,
+                header.scheme
+            );
+            scheme = Some(header.scheme);
         }
+    }
 
-        let end = start + match subslice[start..].binary_search(&max) {
-            Ok(idx) | Err(idx) => idx,
-        } + 1;
+    let scheme = scheme.unwrap_or(index::header::SCHEME_SIMPLIFIED);
 
-        let end = if end > subslice.len() {
-            subslice.len()
+    let strings = strings.unwrap_or_else(|| {
+        if index::names::is_binary_pack(&pack_name) {
+            index::strings::DEFAULT_MIN_RUN
         } else {
-            end
-        };
+            0
+        }
+    });
+
+    let pack_len = fs::metadata(pack_path).unwrap().len();
+    assert!(
+        pack_len >= start,
+        "the pack is shorter than its index; delete {:?} to rebuild it",
+        idx
+    );
+
+    if pack_len == start {
+        println!("{:?} is already up to date", idx);
+        return;
+    }
 
-        // Then, for each of those, save the document ids in the appropriate place.
-        for tri in &subslice[start..end] {
-            if let Some(v) = tris.get_mut(tri) {
-                v.push(part.pos);
+    let pack = catfight::Pack::open(pack_path).unwrap();
+
+    // First, we read the pack once, through, sorting every (trigram, pos) pair we see,
+    // spilling sorted runs to disk whenever we run out of memory.
+    let mut sorter = sort::Sorter::new(&options.temp_dir, options.memory);
+    let (entries, pack_len) = read_pack_trigrams(
+        pack,
+        start,
+        strings,
+        index::tri::scheme(scheme).unwrap(),
+        options.threads,
+        &mut sorter,
+    );
+
+    if 0 == entries {
+        println!("{:?} is already up to date", idx);
+        return;
+    }
+
+    // It's written under another name, then renamed, so a half-written one is never opened.
+    let out_path = if index::header::PACK_START == start {
+        idx.to_path_buf()
+    } else {
+        delta_path(idx, start)
+    };
+    let temp_path = out_path.with_extension("partial");
+
+    let mut out = IndexWriter::create(
+        &temp_path,
+        index::header::Header {
+            scheme,
+            checksum: 0,
+            strings,
+            format: index::find::FORMAT_PACKED,
+            packs: vec![index::header::PackRange {
+                name: pack_name,
+                addendum,
+                start,
+                pack_len,
+            }],
+        },
+    );
+
+    // Merging the runs gives us each trigram's poses, in order, one trigram after another:
+    // [{A, B, C}, {A, C, E}] is now [A1, A2, B1, C1, C2, E2]. Write each list as it ends.
+    let mut current = None;
+    let mut poses: Vec<Pos> = Vec::new();
+
+    for (tri, pos) in sorter.into_sorted().unwrap() {
+        if Some(tri) != current {
+            if let Some(last) = current {
+                out.push_poses(last, &poses);
+                poses.clear();
             }
+            current = Some(tri);
         }
 
-        run += part.num_tris as usize;
+        poses.push(pos);
+    }
+
+    if let Some(last) = current {
+        out.push_poses(last, &poses);
     }
 
-    assert_eq!(temp_data.len(), run);
+    out.finish();
+    fs::rename(&temp_path, &out_path).unwrap();
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+/// Fold the deltas for `idx` back into it, so there's only one segment to open.
+fn compact(idx: &path::Path) {
+    let deltas = deltas(idx);
+    if deltas.is_empty() {
+        println!("{:?} has no deltas to compact", idx);
+        return;
+    }
+
+    let segments = open_segments(idx);
 
-    let fp = io::BufReader::new(fs::File::open(&args[1]).unwrap());
-    let mut out = io::BufWriter::new(fs::File::create(&args[2]).unwrap());
+    let mut header = segments[0].header.clone();
+    header.packs[0].pack_len = segments[segments.len() - 1].header.packs[0].pack_len;
 
-    // First, we transform the pack into just the trigrams for each item in the pack,
-    // remembering where those trigrams referred to, stored in a `temp`orary file.
-    let (temp, temp_index, trigram_count) = convert_pack_to_just_trigrams(fp);
+    let temp_path = idx.with_extension("compacting");
+    let mut out = IndexWriter::create(&temp_path, header);
 
-    // Sort the trigrams we've seen by number.
-    let mut trigram_count: Vec<(Tri, Count)> =
-        trigram_count.into_iter().map(|x| (x.0, x.1)).collect();
-    trigram_count.sort();
-    let mut trigram_count = trigram_count.into_iter();
+    // The segments cover consecutive parts of the pack, so each trigram's lists just join up.
+    for tri in 0..segments[0].scheme.max_tri() {
+        let mut ids = Vec::new();
+        for segment in &segments {
+            ids.extend(segment.postings(tri).unwrap().to_vec().unwrap());
+        }
+        out.push(tri, &ids);
+    }
 
-    // ..which we immediately map into memory as an array of Tri(u32)s.
-    let map = unsafe { memmap::MmapOptions::new().map(&temp) }.unwrap();
-    let temp_data = unsafe { std::slice::from_raw_parts((map.as_ptr()) as *const Tri, map.len() / 4) };
+    out.finish();
+    std::mem::drop(segments);
+
+    // The deltas are ignored once the base covers them, so it's fine to stop between these.
+    fs::rename(&temp_path, idx).unwrap();
+    for delta in deltas {
+        fs::remove_file(delta).unwrap();
+    }
+}
 
-    // Now, we want to transpose [{A, B, C}, {A, C, E}] into [A: {1, 2}, B: {1}, C: {1, 2}, E: {2}]
+/// Merge the indexes of some packs, which have stopped growing, into one segment at `out`,
+/// so they're searched with one intersection instead of one each. The inputs are removed.
+fn merge(out: &path::Path, idxs: &[&path::Path]) {
+    assert!(!out.exists(), "{:?} already exists", out);
+
+    let out_dir = fs::canonicalize(dir_of(out)).unwrap();
+
+    let mut inputs = Vec::with_capacity(idxs.len());
+    for &idx in idxs {
+        assert_eq!(
+            out_dir,
+            fs::canonicalize(dir_of(idx)).unwrap(),
+            "{:?} is in a different directory to {:?}, so its pack would be lost",
+            idx,
+            out
+        );
+
+        let segments = open_segments(idx);
+        assert_eq!(
+            1,
+            segments[0].header.packs.len(),
+            "{:?} is already merged",
+            idx
+        );
+        inputs.push((idx, segments));
+    }
 
-    // Let's do this in blocks, so we don't run out of memory.
-    loop {
-        // Select a block of trigrams to process, and allocate space for their documents.
-        let mut tri_poses = take_some(&mut trigram_count);
+    // so the pack table is in the order Index searches them in
+    inputs.sort_by(|left, right| left.1[0].packs[0].cmp(&right.1[0].packs[0]));
+
+    let (scheme, strings, binary) = {
+        let first = &inputs[0].1[0];
+        (
+            first.header.scheme,
+            first.header.strings,
+            index::names::is_binary_pack(&first.header.packs[0].name),
+        )
+    };
+
+    let mut packs: Vec<index::header::PackRange> = Vec::with_capacity(inputs.len());
+    for &(idx, ref segments) in &inputs {
+        let first = &segments[0].header;
+        assert!(
+            scheme == first.scheme
+                && strings == first.strings
+                && binary == index::names::is_binary_pack(&first.packs[0].name),
+            "{:?} wasn't indexed the same way as {:?}",
+            idx,
+            inputs[0].0
+        );
+
+        let name = &first.packs[0].name;
+        assert!(
+            packs.last().map_or(true, |last| &last.name != name),
+            "{} is in more than one of the indexes",
+            name
+        );
+
+        packs.push(index::header::PackRange {
+            pack_len: segments[segments.len() - 1].header.packs[0].pack_len,
+            ..first.packs[0].clone()
+        });
+    }
 
-        if tri_poses.is_empty() {
-            break;
+    let temp_path = out.with_extension("merging");
+    let mut writer = IndexWriter::create(
+        &temp_path,
+        index::header::Header {
+            scheme,
+            checksum: 0,
+            strings,
+            format: index::find::FORMAT_PACKED,
+            packs,
+        },
+    );
+
+    // The pack's number goes in the top of each id, so each trigram's lists still just join up.
+    for tri in 0..inputs[0].1[0].scheme.max_tri() {
+        let mut ids = Vec::new();
+        for (pack_no, &(_, ref segments)) in inputs.iter().enumerate() {
+            for segment in segments {
+                ids.extend(
+                    segment
+                        .postings(tri)
+                        .unwrap()
+                        .to_vec()
+                        .unwrap()
+                        .into_iter()
+                        .map(|local| (pack_no as u64) << 32 | local),
+                );
+            }
         }
+        writer.push(tri, &ids);
+    }
 
-        // Fetch the document ids for each of the selected trigrams, by scanning the temp data.
-        fill_tris(&temp_index, &temp_data, &mut tri_poses);
+    writer.finish();
+    std::mem::drop(inputs);
 
-        let mut tris: Vec<Tri> = tri_poses.keys().map(|x| *x).collect();
-        tris.sort();
+    // Index refuses to open a pack which is in two indexes, so this isn't safe to stop in.
+    fs::rename(&temp_path, out).unwrap();
+    for &idx in idxs {
+        for delta in deltas(idx) {
+            fs::remove_file(delta).unwrap();
+        }
+        fs::remove_file(idx).unwrap();
+    }
+}
+
+/// The first record in `pack` after those in the `moved` map from compacting it, which hasn't been
+/// deleted, and so was added since; it'd be lost if `pack` were replaced.
+fn appended_since(
+    pack: &catfight::Pack,
+    moved: &BTreeMap<u64, u64>,
+) -> catfight::Result<Option<u64>> {
+    let compacted_end = match moved.keys().next_back() {
+        Some(&last) => last + pack.get(last)?.len(),
+        None => index::header::PACK_START,
+    };
+
+    for record in pack.records(compacted_end) {
+        let (pos, record) = record?;
+        if !record.tombstone {
+            return Ok(Some(pos));
+        }
+    }
 
-        // Write them out.
+    Ok(None)
+}
 
-        // Format (everything is a u32):
-        // file: [block] [block..] 0
-        // block: [num headers] [header] [header..] [poses] [poses..]
-        // header: [tri] [num tris]
-        // poses: ..just the raw data
+/// Every record in `pack`, as a `pos`, with its `pos` after compacting, as the `moved` map from
+/// compacting it says, or `None` if it was dropped.
+fn pos_map(
+    pack: &catfight::Pack,
+    addendum: u64,
+    moved: &BTreeMap<u64, u64>,
+) -> catfight::Result<Vec<(u64, Option<u64>)>> {
+    let mut out = Vec::new();
+    for record in pack.records(index::header::PACK_START) {
+        let (pos, _) = record?;
+        out.push((addendum + pos, moved.get(&pos).map(|new| addendum + new)));
+    }
+    Ok(out)
+}
 
-        // num headers
-        out.write_u32::<LittleEndian>(0xD81F).unwrap();
-        out.write_u32::<LittleEndian>(0).unwrap();
-        out.write_u32::<LittleEndian>(0).unwrap();
-        out.write_u32::<LittleEndian>(tri_poses.len() as u32)
-            .unwrap();
+/// After `catfight compact` has written `new`, a copy of the pack without its deleted records,
+/// rewrite `idx` and its deltas as one segment with each document where the `map` says it moved,
+/// dropping those it doesn't mention, then put `new` in place of the pack. Prints each record's
+/// old `pos`, and its new one or `\N` if it was dropped, for `COPY`ing into the database.
+fn remap(map: &path::Path, new: &path::Path, idx: &path::Path) {
+    // old offset -> new offset, one per tab-separated line, in order
+    let mut moved = BTreeMap::new();
+    for line in io::BufReader::new(fs::File::open(map).unwrap()).lines() {
+        let line = line.unwrap();
+        let mut parts = line.split('\t').map(|part| part.parse::<u64>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(old)), Some(Ok(new)), None) => {
+                moved.insert(old, new);
+            }
+            _ => panic!("{:?} isn't a map from catfight compact: {:?}", map, line),
+        }
+    }
 
-        for tri in &tris {
-            out.write_u32::<LittleEndian>(*tri).unwrap();
+    let new_len = catfight::Pack::open(new).unwrap().end().unwrap();
+
+    let segments = open_segments(idx);
+    assert_eq!(
+        1,
+        segments[0].header.packs.len(),
+        "{:?} is merged; rebuild it instead",
+        idx
+    );
+
+    let pack = segments[0].packs[0].clone();
+
+    // Writers wait on this lock; once they have it, they see the pack has been replaced, and
+    // reopen it, so nothing is appended to the old one after it's checked.
+    let lock = fs::File::open(&pack).unwrap();
+    catfight::flock(&lock).unwrap();
+    let old = catfight::Pack::open(&pack).unwrap();
+    if let Some(pos) = appended_since(&old, &moved).unwrap() {
+        panic!(
+            "{:?} has a record at {}, added since it was compacted; compact it again",
+            pack, pos
+        );
+    }
 
-            let poses = &tri_poses[tri];
-            assert!(poses.len() <= std::u32::MAX as usize);
-            out.write_u32::<LittleEndian>(poses.len() as u32).unwrap();
+    let poses = pos_map(&old, segments[0].header.packs[0].addendum, &moved).unwrap();
+
+    let covered = segments[segments.len() - 1].header.packs[0].pack_len;
+
+    let mut header = segments[0].header.clone();
+
+    // anything the index didn't cover yet is still after everything it did
+    header.packs[0].pack_len = moved
+        .range(covered..)
+        .next()
+        .map_or(new_len, |(_, &new)| new);
+
+    let temp_path = idx.with_extension("remapping");
+    let mut out = IndexWriter::create(&temp_path, header);
+
+    // Records only move towards the start, and never past each other, so the lists stay in order.
+    for tri in 0..segments[0].scheme.max_tri() {
+        let mut ids = Vec::new();
+        for segment in &segments {
+            ids.extend(
+                segment
+                    .postings(tri)
+                    .unwrap()
+                    .to_vec()
+                    .unwrap()
+                    .into_iter()
+                    .filter_map(|old| moved.get(&old).cloned()),
+            );
         }
+        out.push(tri, &ids);
+    }
 
-        for tri in tris {
-            let poses = &tri_poses[&tri];
+    out.finish();
+    std::mem::drop(segments);
+
+    // Until the pack is renamed too, searches of it will find the wrong documents.
+    fs::rename(&temp_path, idx).unwrap();
+    fs::rename(new, &pack).unwrap();
+    for delta in deltas(idx) {
+        fs::remove_file(delta).unwrap();
+    }
+
+    for (old, new) in poses {
+        match new {
+            Some(new) => println!("{}\t{}", old, new),
+            None => println!("{}\t\\N", old),
+        }
+    }
+}
 
-            for pos in poses {
-                out.write_u32::<LittleEndian>(*pos).unwrap();
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "usage: {} [--memory MB] [--temp-dir DIR] [--threads N] [--strings N] \
+         [--scheme simplified|bytes] PACK IDX, or {} --compact IDX, or {} --merge OUT IDX.., \
+         or {} --remap MAP NEW IDX",
+        program, program, program, program
+    );
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let mut options = Options {
+        memory: DEFAULT_MEMORY,
+        temp_dir: env::temp_dir(),
+        threads: num_cpus::get(),
+        strings: None,
+        scheme: None,
+    };
+    let mut compacting = false;
+    let mut merging = false;
+    let mut remapping = None;
+    let mut paths = Vec::new();
+
+    let mut it = args[1..].iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--memory" => {
+                options.memory = match it.next().map(|mb| mb.parse::<usize>()) {
+                    Some(Ok(mb)) if mb > 0 => mb * 1024 * 1024,
+                    _ => usage(&args[0]),
+                }
+            }
+            "--threads" => {
+                options.threads = match it.next().map(|threads| threads.parse::<usize>()) {
+                    Some(Ok(threads)) if threads > 0 => threads,
+                    _ => usage(&args[0]),
+                }
+            }
+            "--strings" => {
+                options.strings = match it.next().map(|strings| strings.parse::<u32>()) {
+                    Some(Ok(strings)) => Some(strings),
+                    _ => usage(&args[0]),
+                }
+            }
+            "--scheme" => {
+                options.scheme = match it.next().map(|scheme| scheme.as_str()) {
+                    Some("simplified") => Some(index::header::SCHEME_SIMPLIFIED),
+                    Some("bytes") => Some(index::header::SCHEME_BYTES),
+                    _ => usage(&args[0]),
+                }
             }
+            "--temp-dir" => match it.next() {
+                Some(dir) => options.temp_dir = path::PathBuf::from(dir),
+                None => usage(&args[0]),
+            },
+            "--compact" => compacting = true,
+            "--merge" => merging = true,
+            "--remap" => match it.next() {
+                Some(map) => remapping = Some(path::Path::new(map)),
+                None => usage(&args[0]),
+            },
+            _ => paths.push(path::Path::new(arg)),
         }
     }
 
-    // zero item header -> end of file
-    out.write_u32::<LittleEndian>(0).unwrap();
+    if merging {
+        if compacting || remapping.is_some() || paths.len() < 2 {
+            usage(&args[0]);
+        }
+        merge(paths[0], &paths[1..]);
+        return;
+    }
+
+    match (compacting, remapping, paths.as_slice()) {
+        (true, None, &[idx]) => compact(idx),
+        (false, Some(map), &[new, idx]) => remap(map, new, idx),
+        (false, None, &[pack, idx]) => reindex(pack, idx, &options),
+        _ => usage(&args[0]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+
+    use catfight;
+    use tempfile::NamedTempFile;
+
+    use super::appended_since;
+    use super::pos_map;
+
+    /// Write a record, returning where it is.
+    fn append(pack: &mut fs::File, body: &[u8]) -> u64 {
+        let mut src = NamedTempFile::new().unwrap();
+        src.write_all(body).unwrap();
+        src.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut end = pack.seek(SeekFrom::End(0)).unwrap();
+        catfight::flock(pack).unwrap();
+        catfight::writey_write(pack, &mut end, &mut src, body.len() as u64, &[]).unwrap();
+
+        // past the pack's header, if this was the first
+        end
+    }
+
+    #[test]
+    fn appended() {
+        let mut file = NamedTempFile::new().unwrap();
+        let first = append(&mut file, b"one");
+        let second = append(&mut file, b"two");
+        catfight::delete(file.path(), &[second]).unwrap();
+
+        let mut out = NamedTempFile::new().unwrap();
+        let moved: BTreeMap<u64, u64> =
+            catfight::compact(&catfight::Pack::open(file.path()).unwrap(), &mut out)
+                .unwrap()
+                .into_iter()
+                .collect();
+        assert_eq!(vec![first], moved.keys().cloned().collect::<Vec<_>>());
+
+        // the deleted record was dropped, so it's fine that it's after the last one kept
+        let pack = catfight::Pack::open(file.path()).unwrap();
+        assert_eq!(None, appended_since(&pack, &moved).unwrap());
+
+        let third = append(&mut file, b"three");
+        assert_eq!(Some(third), appended_since(&pack, &moved).unwrap());
+    }
+
+    #[test]
+    fn poses() {
+        let mut file = NamedTempFile::new().unwrap();
+        let first = append(&mut file, b"one");
+        let second = append(&mut file, b"two");
+        let third = append(&mut file, b"three");
+        catfight::delete(file.path(), &[second]).unwrap();
+
+        let mut out = NamedTempFile::new().unwrap();
+        let pack = catfight::Pack::open(file.path()).unwrap();
+        let moved: BTreeMap<u64, u64> = catfight::compact(&pack, &mut out)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(Some(&second), moved.get(&third));
+
+        let (_, addendum) =
+            index::names::addendum_from_path("text-5.0000000000000000000002").unwrap();
+        assert_eq!(
+            vec![
+                (addendum + first, Some(addendum + first)),
+                (addendum + second, None),
+                (addendum + third, Some(addendum + second)),
+            ],
+            pos_map(&pack, addendum, &moved).unwrap()
+        );
+    }
 }
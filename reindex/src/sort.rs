@@ -0,0 +1,168 @@
+//! An external merge sort of (trigram, pos) pairs.
+//!
+//! Pairs are gathered in memory until they hit the limit, then sorted and written out as a run.
+//! Reading them back is a k-way merge of the runs, so every pair is written and read exactly once.
+
+use std::cmp;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path;
+use std::vec;
+
+use std::collections::BinaryHeap;
+use std::io::Seek;
+use std::io::SeekFrom;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use tempfile;
+
+use Pos;
+use Tri;
+
+/// Bytes of read buffer for each run, while merging.
+const RUN_BUFFER: usize = 64 * 1024;
+
+pub struct Sorter {
+    temp_dir: path::PathBuf,
+
+    /// Unsorted pairs, packed as `tri << 32 | pos`, so sorting them sorts by trigram, then pos.
+    pending: Vec<u64>,
+    max_pending: usize,
+
+    /// Sorted, on disk.
+    runs: Vec<fs::File>,
+}
+
+impl Sorter {
+    /// Spill runs into `temp_dir`, keeping at most about `memory` bytes of pairs in memory.
+    pub fn new<P: AsRef<path::Path>>(temp_dir: P, memory: usize) -> Sorter {
+        let max_pending = cmp::max(1, memory / mem::size_of::<u64>());
+        Sorter {
+            temp_dir: temp_dir.as_ref().to_path_buf(),
+            pending: Vec::with_capacity(max_pending),
+            max_pending,
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, tri: Tri, pos: Pos) -> io::Result<()> {
+        self.pending.push((tri as u64) << 32 | pos as u64);
+        if self.pending.len() >= self.max_pending {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        self.pending.sort_unstable();
+
+        let mut run = io::BufWriter::new(tempfile::tempfile_in(&self.temp_dir)?);
+        for pair in self.pending.drain(..) {
+            run.write_u64::<LittleEndian>(pair)?;
+        }
+
+        let mut run = run.into_inner().map_err(|e| e.into_error())?;
+        run.seek(SeekFrom::Start(0))?;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    /// Every pair pushed, in order. If nothing had to be spilled, nothing touches the disk.
+    pub fn into_sorted(mut self) -> io::Result<Merge> {
+        if self.runs.is_empty() {
+            self.pending.sort_unstable();
+            return Ok(Merge {
+                sources: vec![Source::Memory(self.pending.into_iter())],
+                heap: BinaryHeap::new(),
+                started: false,
+            });
+        }
+
+        if !self.pending.is_empty() {
+            self.spill()?;
+        }
+
+        // free the buffer before the merge starts needing memory of its own
+        let Sorter { runs, .. } = self;
+
+        Ok(Merge {
+            sources: runs
+                .into_iter()
+                .map(|run| Source::Run(io::BufReader::with_capacity(RUN_BUFFER, run)))
+                .collect(),
+            heap: BinaryHeap::new(),
+            started: false,
+        })
+    }
+}
+
+enum Source {
+    Memory(vec::IntoIter<u64>),
+    Run(io::BufReader<fs::File>),
+}
+
+impl Source {
+    fn next(&mut self) -> Option<u64> {
+        match *self {
+            Source::Memory(ref mut pairs) => pairs.next(),
+            Source::Run(ref mut run) => match run.read_u64::<LittleEndian>() {
+                Ok(pair) => Some(pair),
+                Err(ref e) if io::ErrorKind::UnexpectedEof == e.kind() => None,
+                Err(e) => panic!("reading a sorted run: {}", e),
+            },
+        }
+    }
+}
+
+/// The k-way merge of the runs: the heap holds the next pair from each, smallest on top.
+pub struct Merge {
+    sources: Vec<Source>,
+    heap: BinaryHeap<cmp::Reverse<(u64, usize)>>,
+    started: bool,
+}
+
+impl Iterator for Merge {
+    type Item = (Tri, Pos);
+
+    fn next(&mut self) -> Option<(Tri, Pos)> {
+        if !self.started {
+            for (i, source) in self.sources.iter_mut().enumerate() {
+                if let Some(pair) = source.next() {
+                    self.heap.push(cmp::Reverse((pair, i)));
+                }
+            }
+            self.started = true;
+        }
+
+        let cmp::Reverse((pair, i)) = self.heap.pop()?;
+        if let Some(next) = self.sources[i].next() {
+            self.heap.push(cmp::Reverse((next, i)));
+        }
+
+        Some(((pair >> 32) as Tri, pair as Pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::Sorter;
+
+    #[test]
+    fn merge() {
+        let pairs: Vec<(u32, u32)> = (0..1000u32).map(|i| (i * 7919 % 97, i * 16)).collect();
+        let mut expected = pairs.clone();
+        expected.sort();
+
+        // all in memory, one pair per run, and somewhere in between
+        for &memory in &[1 << 20, 8, 8 * 37] {
+            let mut sorter = Sorter::new(env::temp_dir(), memory);
+            for &(tri, pos) in &pairs {
+                sorter.push(tri, pos).unwrap();
+            }
+            assert_eq!(expected, sorter.into_sorted().unwrap().collect::<Vec<_>>());
+        }
+    }
+}
@@ -15,12 +15,13 @@ extern crate stderrlog;
 extern crate url;
 
 use std::fs;
+use std::time;
 use std::io::Read as IoRead;
-use std::io::Seek;
-use std::io::SeekFrom;
 
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
 
 use byteorder::{ByteOrder, LittleEndian};
 
@@ -29,8 +30,13 @@ use iron::headers::ContentType;
 use iron::status;
 use router::Router;
 
+use index::ResultExt;
 use persistent::Read;
 use r2d2::Pool;
+
+/// How long a single search request may spend grepping.
+const SEARCH_BUDGET: time::Duration = time::Duration::from_secs(10);
+
 pub struct AppDb;
 impl iron::typemap::Key for AppDb {
     type Value = Pool<r2d2_postgres::PostgresConnectionManager>;
@@ -41,6 +47,12 @@ impl iron::typemap::Key for AppIndex {
     type Value = index::find::Index<'static>;
 }
 
+/// Every pack `cat` has opened, by name; a `Pack` can be read by any number of requests at once.
+pub struct AppPacks;
+impl iron::typemap::Key for AppPacks {
+    type Value = RwLock<HashMap<String, Arc<catfight::Pack>>>;
+}
+
 enum Oid {
     Pos(i64),
     Hash(i64, i64, i64, i64),
@@ -125,18 +137,29 @@ fn cat(req: &mut Request) -> IronResult<Response> {
         Oid::Pos(i) => {
             let (name, off) = index::names::filename_for(i as u64);
             println!("{} {}", name, off);
-            let mut fd = fs::File::open(format!("/mnt/data/t/{}", name)).unwrap();
-            fd.seek(SeekFrom::Start(off as u64)).unwrap();
+
+            let packs = req.get::<Read<AppPacks>>().expect("persistent");
+            let pack = match open_pack(&packs, &name) {
+                Ok(pack) => pack,
+                Err(e) => return error_response(status::NotFound, &e),
+            };
+
+            let mut record = match pack.get(off as u64) {
+                Ok(record) => record,
+                Err(e) => return error_response(status::NotFound, &e),
+            };
+            if !record.readable() {
+                return Ok(Response::with(status::Gone));
+            }
+
+            // it's in the pack, so if it can't be read, the pack's broken, not the request
             let mut data = Vec::new();
-            if let Some(mut record) = catfight::read_record(&mut fd).unwrap() {
-                lz4::Decoder::new(&mut record.reader)
-                    .expect("lz4")
-                    .read_to_end(&mut data)
-                    .expect("read");
-                record.complete().expect("complete");
-            } else {
-                panic!()
+            if let Err(e) = lz4::Decoder::new(&mut record.reader)
+                .and_then(|mut decoder| decoder.read_to_end(&mut data))
+            {
+                return error_response(status::InternalServerError, &e);
             }
+
             Ok(Response::with((
                 status::Ok,
                 ContentType::plaintext().0,
@@ -147,6 +170,24 @@ fn cat(req: &mut Request) -> IronResult<Response> {
     }
 }
 
+/// The pack called `name`, opening it if it hasn't been already.
+fn open_pack(
+    packs: &RwLock<HashMap<String, Arc<catfight::Pack>>>,
+    name: &str,
+) -> catfight::Result<Arc<catfight::Pack>> {
+    if let Some(pack) = packs.read().unwrap().get(name) {
+        return Ok(pack.clone());
+    }
+
+    let pack = Arc::new(catfight::Pack::open(format!("/mnt/data/t/{}", name))?);
+    Ok(packs
+        .write()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert(pack)
+        .clone())
+}
+
 fn paths(req: &mut Request) -> IronResult<Response> {
     let pos = if let Oid::Pos(pos) = oid_from_request(req).unwrap() {
         pos
@@ -213,17 +254,23 @@ fn paths(req: &mut Request) -> IronResult<Response> {
 }
 
 fn tri_num(req: &mut Request) -> IronResult<Response> {
-    let tri = req.extensions
+    let tri = match req.extensions
         .get::<Router>()
         .unwrap()
         .find("num")
         .unwrap()
         .parse::<u32>()
-        .unwrap();
+    {
+        Ok(tri) => tri,
+        Err(e) => return bad_request(&e),
+    };
 
     let index = req.get::<Read<AppIndex>>().expect("persistent");
 
-    let docs = index.documents_for_tri(tri);
+    let docs = match index.documents_for_tri(tri) {
+        Ok(docs) => docs,
+        Err(e) => return search_error(&e),
+    };
     Ok(Response::with((
         status::Ok,
         ContentType::json().0,
@@ -241,23 +288,305 @@ fn search(req: &mut Request) -> IronResult<Response> {
         .unwrap()
         .to_string();
 
-    let term = url::percent_encoding::percent_decode(term.as_bytes())
-        .decode_utf8()
-        .expect("query")
+    let term = match url::percent_encoding::percent_decode(term.as_bytes()).decode_utf8() {
+        Ok(term) => term.to_string(),
+        Err(e) => return bad_request(&e),
+    };
+
+    let query = match index::query::parse(&term) {
+        Ok(query) => query,
+        Err(e) => return bad_request(&e),
+    };
+
+    let expr = match query.expr {
+        Some(expr) => expr,
+        None => return bad_request(&"there's nothing to search for, only filters"),
+    };
+
+    let mut options = match search_options(req) {
+        Ok(options) => options,
+        Err(e) => return bad_request(&e),
+    };
+
+    filter_by(req, &mut options, query.filters);
+
+    let index = req.get::<Read<AppIndex>>().expect("persistent");
+    let search = match index.documents_for_query(&expr, &options) {
+        Ok(search) => search,
+        Err(e) => return search_error(&e),
+    };
+
+    search_response(req, &search)
+}
+
+fn regex(req: &mut Request) -> IronResult<Response> {
+    let term: String = req.extensions
+        .get::<Router>()
+        .unwrap()
+        .find("term")
+        .unwrap()
         .to_string();
 
+    let term = match url::percent_encoding::percent_decode(term.as_bytes()).decode_utf8() {
+        Ok(term) => term.to_string(),
+        Err(e) => return bad_request(&e),
+    };
+
+    let mut options = match search_options(req) {
+        Ok(options) => options,
+        Err(e) => return bad_request(&e),
+    };
+
+    // operators can't go in the regex, so they're passed separately, like `?filter=path:src/`
+    let filters = match query_param(req, "filter").map(|filter| index::query::parse(&filter)) {
+        None => Vec::new(),
+        Some(Ok(ref query)) if query.expr.is_some() => {
+            return bad_request(&"the filter can only have operators, like path: or package:")
+        }
+        Some(Ok(query)) => query.filters,
+        Some(Err(e)) => return bad_request(&e),
+    };
+
+    filter_by(req, &mut options, filters);
+
     let index = req.get::<Read<AppIndex>>().expect("persistent");
-    let search = index.documents_for_search(&term);
+    let search = match index.documents_for_regex(&term, &options) {
+        Ok(search) => search,
+        Err(e) => return search_error(&e),
+    };
+
+    search_response(req, &search)
+}
+
+fn bad_request(e: &std::fmt::Display) -> IronResult<Response> {
+    error_response(status::BadRequest, e)
+}
+
+/// The request's fault if it couldn't be understood, otherwise ours, like a broken index.
+fn search_error(e: &index::Error) -> IronResult<Response> {
+    match *e.kind() {
+        index::ErrorKind::InvalidQuery(_)
+        | index::ErrorKind::Regex(_)
+        | index::ErrorKind::RegexSyntax(_) => bad_request(e),
+        _ => error_response(status::InternalServerError, e),
+    }
+}
+
+fn error_response(status: status::Status, e: &std::fmt::Display) -> IronResult<Response> {
     Ok(Response::with((
-        status::Ok,
+        status,
         ContentType::json().0,
         json!({
-            "docs": search.docs,
-            "grepped": search.grepped,
+            "error": format!("{}", e),
         }).to_string(),
     )))
 }
 
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.url.query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|&(ref key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    })
+}
+
+fn search_options(req: &Request) -> index::Result<index::find::SearchOptions> {
+    let mut options = index::find::SearchOptions::default();
+
+    if let Some(context) = query_param(req, "context").and_then(|c| c.parse().ok()) {
+        // snippets, not whole files
+        options.context = std::cmp::min(context, 10);
+    }
+
+    options.case_insensitive = query_param(req, "i").map_or(false, |i| "1" == i);
+    options.binary = query_param(req, "binary").map_or(false, |b| "1" == b);
+
+    let limit = query_param(req, "limit")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(100);
+    options.limit = Some(std::cmp::max(1, std::cmp::min(limit, 1000)));
+
+    if let Some(after) = query_param(req, "after") {
+        options.after = Some(after.parse()?);
+    }
+
+    // don't let one query hog a worker; they can always ask for the `next` page
+    options.deadline = Some(time::Instant::now() + SEARCH_BUDGET);
+
+    Ok(options)
+}
+
+/// Only grep documents which the `filters` keep, if there are any.
+fn filter_by(
+    req: &mut Request,
+    options: &mut index::find::SearchOptions,
+    filters: Vec<index::query::Filter>,
+) {
+    if filters.is_empty() {
+        return;
+    }
+
+    let pool = req.get::<Read<AppDb>>().expect("persistent");
+    options.filter = Some(Arc::new(DbFilter {
+        pool: (*pool).clone(),
+        filters,
+    }));
+}
+
+/// Applies the `path:`, `ext:` and `package:` operators by looking up where the candidates came from.
+struct DbFilter {
+    pool: Pool<r2d2_postgres::PostgresConnectionManager>,
+    filters: Vec<index::query::Filter>,
+}
+
+impl index::find::DocumentFilter for DbFilter {
+    fn retain(&self, docs: &[u64]) -> index::Result<HashSet<u64>> {
+        use index::query::FilterKind;
+
+        let mut sql = "SELECT DISTINCT file.pos FROM file \
+                       JOIN container ON container.id = file.container \
+                       WHERE file.pos = ANY ($1)"
+            .to_string();
+        let mut values = Vec::with_capacity(self.filters.len());
+
+        for filter in &self.filters {
+            let param = values.len() + 2;
+            let condition = match filter.kind {
+                FilterKind::Path => {
+                    values.push(format!("%{}%", like_escape(&filter.value)));
+                    format!(
+                        "EXISTS (SELECT 1 FROM path_component \
+                         WHERE id = ANY (file.paths) AND path LIKE ${})",
+                        param
+                    )
+                }
+                FilterKind::Ext => {
+                    values.push(format!("%.{}", like_escape(&filter.value)));
+                    format!(
+                        "(SELECT path FROM path_component \
+                         WHERE id = file.paths[array_length(file.paths, 1)]) LIKE ${}",
+                        param
+                    )
+                }
+                FilterKind::Package => {
+                    values.push(filter.value.clone());
+                    // ingest has been storing the info as a json *string*, of a python-style dict
+                    format!(
+                        "COALESCE(container.info->>'package', \
+                         substring(container.info #>> '{{}}' from '''package'': ''([^'']*)''')) = ${}",
+                        param
+                    )
+                }
+            };
+
+            if filter.negated {
+                sql.push_str(&format!(" AND ({}) IS NOT TRUE", condition));
+            } else {
+                sql.push_str(&format!(" AND {}", condition));
+            }
+        }
+
+        let poses: Vec<i64> = docs.iter().map(|pos| *pos as i64).collect();
+        let mut params: Vec<&postgres::types::ToSql> = vec![&poses];
+        for value in &values {
+            params.push(value);
+        }
+
+        let conn = self.pool.get().chain_err(|| "connecting to filter")?;
+        let rows = conn.query(&sql, &params).chain_err(|| "filtering candidates")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| row.get::<usize, i64>(0) as u64)
+            .collect())
+    }
+}
+
+/// Match `value` literally in a `LIKE` pattern.
+fn like_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if '\\' == c || '%' == c || '_' == c {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The docs, in index order, so the cursor still pages through them, with how many containers
+/// each is in.
+fn search_response(req: &mut Request, search: &index::find::SearchResult) -> IronResult<Response> {
+    let pool = req.get::<Read<AppDb>>().expect("persistent");
+    let conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => return error_response(status::InternalServerError, &e),
+    };
+
+    let containers = match containers(&conn, search) {
+        Ok(containers) => containers,
+        Err(e) => return error_response(status::InternalServerError, &e),
+    };
+
+    Ok(Response::with((
+        status::Ok,
+        ContentType::json().0,
+        search_json(search, &containers).to_string(),
+    )))
+}
+
+/// How many containers each doc is in, for the client to rank a page by, if it likes.
+fn containers(
+    conn: &postgres::Connection,
+    search: &index::find::SearchResult,
+) -> postgres::Result<HashMap<u64, i64>> {
+    let stat = conn.prepare_cached(
+        "SELECT pos, COUNT(DISTINCT container) FROM file WHERE pos = ANY ($1) GROUP BY pos",
+    )?;
+
+    let poses: Vec<i64> = search.docs.iter().map(|doc| doc.pos as i64).collect();
+
+    let mut containers = HashMap::with_capacity(poses.len());
+    for row in stat.query(&[&poses])?.into_iter() {
+        containers.insert(row.get::<usize, i64>(0) as u64, row.get::<usize, i64>(1));
+    }
+
+    Ok(containers)
+}
+
+fn search_json(
+    search: &index::find::SearchResult,
+    containers: &HashMap<u64, i64>,
+) -> serde_json::Value {
+    let docs: Vec<serde_json::Value> = search
+        .docs
+        .iter()
+        .map(|doc| {
+            json!({
+                "pos": doc.pos,
+                "containers": containers.get(&doc.pos).cloned().unwrap_or(0),
+                "lines": doc.lines.iter().map(|line| json!({
+                    "line": line.line,
+                    "offset": line.offset,
+                    "matches": line.matches,
+                    "text": line.text,
+                    "before": line.before,
+                    "after": line.after,
+                })).collect::<Vec<serde_json::Value>>(),
+            })
+        })
+        .collect();
+
+    json!({
+        "docs": docs,
+        "grepped": search.grepped,
+        "next": search.next.map(|next| next.to_string()),
+        "truncated": search.truncated.is_some(),
+        "reason": search.truncated.map(|reason| reason.to_string()),
+        "strategy": search.strategy.to_string(),
+    })
+}
+
 fn compose(h0: i64, h1: i64, h2: i64, h3: i64) -> [u8; 256 / 8] {
     let mut hash = [0; 256 / 8];
     LittleEndian::write_i64(&mut hash[0..8], h0);
@@ -306,6 +635,7 @@ fn main() {
     router.get("/ds/cat/:bid", cat, "blob-contents");
     router.get("/ds/paths/:bid", paths, "paths");
     router.get("/ds/search/:term", search, "search");
+    router.get("/ds/regex/:term", regex, "regex");
 
     // Debug:
     router.get("/ds/trinum/:num", tri_num, "trinum");
@@ -316,6 +646,7 @@ fn main() {
     chain.link_before(logger_before);
     chain.link(Read::<AppDb>::both(pool));
     chain.link(Read::<AppIndex>::both(index));
+    chain.link(Read::<AppPacks>::both(RwLock::new(HashMap::new())));
     chain.link_after(logger_after);
 
     Iron::new(chain).http("127.0.01:6918").unwrap();